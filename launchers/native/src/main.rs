@@ -24,7 +24,14 @@ fn set_window_icon(windows: NonSend<WinitWindows>) {
 }
 
 fn main() {
-    let mut app = dota_smash::app();
+    let config = dota_smash::GameConfig::from_args(std::env::args().skip(1));
+    if let Some(frames) = config.headless {
+        info!("Starting launcher: Headless");
+        dota_smash::headless_app(config, frames).run();
+        return;
+    }
+
+    let mut app = dota_smash::app(config);
 
     info!("Starting launcher: Native");
     app.add_startup_system(set_window_icon);
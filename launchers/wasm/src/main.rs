@@ -3,7 +3,7 @@ use stylist::yew::styled_component;
 use stylist::{css, global_style};
 use yew::prelude::*;
 
-use dota_smash::LAUNCHER_TITLE;
+use dota_smash::{GameConfig, LAUNCHER_TITLE};
 
 fn set_window_title(title: &str) {
     web_sys::window()
@@ -13,6 +13,12 @@ fn set_window_title(title: &str) {
         .set_title(title);
 }
 
+fn query_string() -> String {
+    web_sys::window()
+        .and_then(|w| w.location().search().ok())
+        .unwrap_or_default()
+}
+
 fn set_global_css() {
     global_style! {
         r#"
@@ -55,7 +61,8 @@ fn main() {
     // Mount the DOM
     yew::start_app::<Root>();
     // Start the Bevy App
-    let mut app = dota_smash::app();
+    let config = GameConfig::from_query(&query_string());
+    let mut app = dota_smash::app(config);
     info!("Starting launcher: WASM");
     app.run();
 }
@@ -0,0 +1,280 @@
+use std::{collections::HashMap, time::Duration};
+
+use bevy::{
+    math::{Quat, Vec2, Vec3},
+    prelude::{Component, Query, Res, ResMut, Transform},
+    reflect::Reflect,
+};
+use bevy_ggrs::{Rollback, SessionType};
+use bevy_rapier2d::prelude::{RapierContext, Velocity};
+
+use crate::{
+    game::{Fireball, FireballLiveTimer, MatchOutcome},
+    net::FrameCount,
+    player::{AbilityCooldowns, JumpState, Player},
+};
+
+pub const ROLLBACK_CHECKSUM: &str = "rollback_checksum";
+
+// How many frames of checksums we keep around to compare resimulations with
+const CHECKSUM_HISTORY_FRAMES: u32 = 128;
+
+/// Checksums of every this many frames are compared with the peers. The
+/// state of these frames is also kept readable, to report desyncs.
+pub const CHECKSUM_EXCHANGE_INTERVAL: u32 = 30;
+
+const FNV_OFFSET: u64 = 0xcbf2_9ce4_8422_2325;
+const FNV_PRIME: u64 = 0x0000_0100_0000_01b3;
+
+/// Checksum over all rollback state at the end of a frame. It is a rollback
+/// type itself, so GGRS includes it when comparing saved states.
+#[derive(Default, Reflect, Hash, Component)]
+#[reflect(Hash)]
+pub struct StateChecksum {
+    pub frame: u32,
+    pub value: u64,
+}
+
+/// Checksums we computed for past frames. Not rolled back, so a resimulated
+/// frame can be compared against its first simulation.
+#[derive(Default)]
+pub struct ChecksumHistory {
+    frames: HashMap<u32, u64>,
+    /// Readable state of the frames whose checksums are exchanged
+    dumps: HashMap<u32, String>,
+}
+
+impl ChecksumHistory {
+    pub fn get(&self, frame: u32) -> Option<u64> {
+        self.frames.get(&frame).copied()
+    }
+
+    pub fn dump(&self, frame: u32) -> Option<&str> {
+        self.dumps.get(&frame).map(String::as_str)
+    }
+}
+
+/// FNV-1a over the raw bits of the state. Unlike `DefaultHasher` this gives
+/// the same result on every platform, native and WASM peers included.
+pub struct StateHasher(u64);
+
+impl StateHasher {
+    pub fn new() -> Self {
+        Self(FNV_OFFSET)
+    }
+
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        for byte in bytes {
+            self.0 ^= *byte as u64;
+            self.0 = self.0.wrapping_mul(FNV_PRIME);
+        }
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.write_bytes(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.write_u32(value as u32);
+        self.write_u32((value >> 32) as u32);
+    }
+
+    pub fn write_usize(&mut self, value: usize) {
+        // usize is 32 bit on WASM, always hash 32 bits
+        self.write_u32(value as u32);
+    }
+
+    pub fn write_f32(&mut self, value: f32) {
+        self.write_u32(value.to_bits());
+    }
+
+    pub fn write_duration(&mut self, value: Duration) {
+        self.write_u64(value.as_nanos() as u64);
+    }
+
+    pub fn write_vec2(&mut self, value: Vec2) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+    }
+
+    pub fn write_vec3(&mut self, value: Vec3) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+        self.write_f32(value.z);
+    }
+
+    pub fn write_quat(&mut self, value: Quat) {
+        self.write_f32(value.x);
+        self.write_f32(value.y);
+        self.write_f32(value.z);
+        self.write_f32(value.w);
+    }
+
+    pub fn finish(&self) -> u64 {
+        self.0
+    }
+}
+
+impl Default for StateHasher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub fn checksum_system(
+    frame_count: Res<FrameCount>,
+    mut checksum: ResMut<StateChecksum>,
+    mut history: ResMut<ChecksumHistory>,
+    session_type: Option<Res<SessionType>>,
+    rapier_context: Res<RapierContext>,
+    outcome: Res<MatchOutcome>,
+    query: Query<
+        (
+            Option<&Transform>,
+            Option<&Velocity>,
+            Option<&Player>,
+            Option<&AbilityCooldowns>,
+            Option<&JumpState>,
+            Option<&Fireball>,
+            Option<&FireballLiveTimer>,
+        ),
+        With<Rollback>,
+    >,
+) {
+    let frame = frame_count.frame;
+    let dump = frame % CHECKSUM_EXCHANGE_INTERVAL == 0;
+    let mut lines = Vec::new();
+
+    // Rollback ids of entities spawned during a frame differ between
+    // resimulations, so entities are hashed on their own and combined in an
+    // order independent way.
+    let mut value = StateHasher::new();
+    value.write_u32(frame);
+    value.write_u32(outcome.finished as u32);
+    value.write_u32(outcome.finished_at);
+    value.write_usize(outcome.winner);
+    value.write_u32(outcome.has_winner as u32);
+    let mut value = value.finish();
+
+    for components in query.iter() {
+        let (
+            transform,
+            velocity,
+            player,
+            cooldowns,
+            jump,
+            fireball,
+            live_timer,
+        ) = components;
+        let mut hasher = StateHasher::new();
+
+        if let Some(t) = transform {
+            hasher.write_vec3(t.translation);
+            hasher.write_quat(t.rotation);
+            hasher.write_vec3(t.scale);
+        }
+        if let Some(v) = velocity {
+            hasher.write_vec2(v.linvel);
+            hasher.write_f32(v.angvel);
+        }
+        if let Some(p) = player {
+            hasher.write_usize(p.handle);
+            hasher.write_u32(p.facing_left as u32);
+            hasher.write_f32(p.damage);
+            hasher.write_u32(p.hitstun);
+            hasher.write_u32(p.stocks);
+            hasher.write_u32(p.invulnerable);
+        }
+        if let Some(c) = cooldowns {
+            hasher.write_u32(c.primary);
+            hasher.write_u32(c.special);
+        }
+        if let Some(j) = jump {
+            hasher.write_u32(j.grounded as u32);
+            hasher.write_u32(j.air_jumps);
+            hasher.write_u32(j.coyote_frames);
+            hasher.write_u32(j.jump_held as u32);
+        }
+        if let Some(f) = fireball {
+            hasher.write_usize(f.player_handle);
+        }
+        if let Some(t) = live_timer {
+            hasher.write_duration(t.0.elapsed());
+        }
+
+        value = value.wrapping_add(hasher.finish());
+
+        if dump {
+            lines.push(format!("{:?}", components));
+        }
+    }
+
+    // Rapier's own state, which the components above only mirror. Bodies are
+    // stored in an arena, so their order is the same in every simulation.
+    let mut hasher = StateHasher::new();
+    for (handle, body) in rapier_context.bodies.iter() {
+        let (index, generation) = handle.into_raw_parts();
+        hasher.write_u32(index);
+        hasher.write_u32(generation);
+        hasher.write_f32(body.translation().x);
+        hasher.write_f32(body.translation().y);
+        hasher.write_f32(body.rotation().re);
+        hasher.write_f32(body.rotation().im);
+        hasher.write_f32(body.linvel().x);
+        hasher.write_f32(body.linvel().y);
+        hasher.write_f32(body.angvel());
+
+        if dump {
+            lines.push(format!(
+                "body {:?}: {:?} {:?} {:?} {:?}",
+                handle.into_raw_parts(),
+                body.translation(),
+                body.rotation(),
+                body.linvel(),
+                body.angvel()
+            ));
+        }
+    }
+    value = value.wrapping_add(hasher.finish());
+
+    checksum.frame = frame;
+    checksum.value = value;
+
+    // A sync test feeds the exact same inputs into every resimulation, so
+    // any difference to the first simulation of a frame is a desync. In a P2P
+    // session resimulations see corrected inputs and are expected to differ.
+    let sync_test = matches!(
+        session_type.as_deref(),
+        Some(SessionType::SyncTestSession)
+    );
+
+    if sync_test {
+        if let Some(previous) = history.frames.get(&frame) {
+            if *previous != value {
+                panic!(
+                    "Desync detected in sync test at frame {}: \
+                     checksum {:016x} after resimulation, {:016x} before",
+                    frame, value, previous
+                );
+            }
+        }
+    }
+
+    history.frames.insert(frame, value);
+    history
+        .frames
+        .retain(|f, _| f + CHECKSUM_HISTORY_FRAMES > frame);
+
+    if dump {
+        // Entities in the same order on every peer
+        lines.sort();
+        let header = format!(
+            "frame {} checksum {:016x}\n{:?}\n",
+            frame, value, *outcome
+        );
+        history.dumps.insert(frame, header + &lines.join("\n"));
+        history
+            .dumps
+            .retain(|f, _| f + CHECKSUM_HISTORY_FRAMES > frame);
+    }
+}
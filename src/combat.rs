@@ -0,0 +1,77 @@
+use bevy::prelude::{Transform, Vec2, Vec3};
+use bevy_rapier2d::prelude::Velocity;
+use serde::Deserialize;
+
+use crate::{hero::Ability, player::Player};
+
+// Hitstun frames per unit of knockback speed
+const HITSTUN_PER_KNOCKBACK: f32 = 0.04;
+
+/// Damage and knockback of a single hit. Knockback speed grows with the
+/// damage percent the victim has accumulated:
+/// `base_knockback + percent * knockback_growth`.
+#[derive(Clone, Copy, Debug, Deserialize)]
+pub struct Attack {
+    pub damage: f32,
+    pub base_knockback: f32,
+    pub knockback_growth: f32,
+    /// Launch direction for a hit to the right, mirrored for hits to the
+    /// left. A vector instead of an angle, since `sin`/`cos` may round
+    /// differently on native and WASM peers.
+    pub launch: Vec2,
+}
+
+/// Applies `attack` to the victim, knocking it towards `direction` (negative
+/// is left). Returns the resulting knockback speed.
+pub fn apply_hit(
+    attack: &Attack,
+    direction: f32,
+    victim: &mut Player,
+    velocity: &mut Velocity,
+) -> f32 {
+    victim.damage += attack.damage;
+
+    let knockback =
+        attack.base_knockback + victim.damage * attack.knockback_growth;
+
+    velocity.linvel = Vec2::new(
+        direction.signum() * attack.launch.x,
+        attack.launch.y,
+    ) * knockback;
+
+    victim.hitstun = (knockback * HITSTUN_PER_KNOCKBACK) as u32;
+
+    knockback
+}
+
+/// Applies a melee ability cast at `origin` to a single victim, if it is in
+/// range.
+pub fn apply_melee(
+    ability: &Ability,
+    origin: Vec3,
+    victim: &mut Player,
+    transform: &Transform,
+    velocity: &mut Velocity,
+) {
+    let offset = (transform.translation - origin).truncate();
+
+    match ability {
+        Ability::CounterHelix { radius, attack, .. } => {
+            if offset.length_squared() <= radius * radius {
+                apply_hit(attack, offset.x, victim, velocity);
+            }
+        }
+        Ability::BerserkersCall {
+            radius,
+            pull_speed,
+            taunt_frames,
+            ..
+        } => {
+            if offset.length_squared() <= radius * radius {
+                velocity.linvel = -offset.normalize_or_zero() * *pull_speed;
+                victim.hitstun = victim.hitstun.max(*taunt_frames);
+            }
+        }
+        Ability::Fireball(_) => (),
+    }
+}
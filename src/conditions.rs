@@ -0,0 +1,137 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use bevy::utils::Instant;
+
+// Extra delay of packets picked for reordering, enough for the packets sent
+// after them to overtake them
+const REORDER_DELAY: Duration = Duration::from_millis(50);
+
+/// Artificial network conditions, applied to packets on their way to a
+/// peer.
+#[derive(Clone, Copy, PartialEq, Debug, Default)]
+pub struct NetworkConditions {
+    /// Delay of every packet
+    pub latency: Duration,
+    /// Up to this much is randomly added to or taken off the latency.
+    /// Packets overtake each other when it exceeds their spacing.
+    pub jitter: Duration,
+    /// Chance of a packet getting lost, between 0 and 1
+    pub loss: f32,
+    /// Chance of a packet arriving twice, between 0 and 1
+    pub duplicate: f32,
+    /// Chance of a packet being held back until later ones passed it,
+    /// between 0 and 1
+    pub reorder: f32,
+}
+
+impl NetworkConditions {
+    /// Whether packets are sent as they are.
+    pub fn is_perfect(&self) -> bool {
+        *self == NetworkConditions::default()
+    }
+}
+
+/// Conditions of the session socket, changed live from the debug window.
+/// Clones share the conditions. The seed decides which packets are lost,
+/// duplicated or reordered, a run is reproduced by passing it again.
+#[derive(Clone)]
+pub struct NetworkSimulator {
+    conditions: Arc<Mutex<NetworkConditions>>,
+    seed: u64,
+}
+
+impl Default for NetworkSimulator {
+    fn default() -> Self {
+        Self::with_seed(fastrand::u64(..))
+    }
+}
+
+impl NetworkSimulator {
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            conditions: Arc::default(),
+            seed,
+        }
+    }
+
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    pub fn conditions(&self) -> NetworkConditions {
+        *self.conditions.lock().unwrap()
+    }
+
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        *self.conditions.lock().unwrap() = conditions;
+    }
+}
+
+/// Packets in flight under some `NetworkConditions`, handed out once their
+/// delay has passed.
+pub struct DelayLine<T> {
+    in_flight: Vec<(Instant, T)>,
+    rng: fastrand::Rng,
+}
+
+impl<T> Default for DelayLine<T> {
+    fn default() -> Self {
+        Self {
+            in_flight: Vec::new(),
+            rng: fastrand::Rng::new(),
+        }
+    }
+}
+
+impl<T> DelayLine<T> {
+    /// Makes the same choices for the same packets on every run.
+    pub fn with_seed(seed: u64) -> Self {
+        Self {
+            in_flight: Vec::new(),
+            rng: fastrand::Rng::with_seed(seed),
+        }
+    }
+}
+
+impl<T: Clone> DelayLine<T> {
+    pub fn send(&mut self, conditions: &NetworkConditions, packet: T) {
+        if self.rng.f32() < conditions.loss {
+            return;
+        }
+
+        if self.rng.f32() < conditions.duplicate {
+            self.schedule(conditions, packet.clone());
+        }
+        self.schedule(conditions, packet);
+    }
+
+    /// Packets that arrived by now, in order of arrival.
+    pub fn receive(&mut self) -> Vec<T> {
+        let now = Instant::now();
+
+        let (mut arrived, in_flight): (Vec<_>, Vec<_>) = self
+            .in_flight
+            .drain(..)
+            .partition(|(arrival, _)| *arrival <= now);
+        self.in_flight = in_flight;
+
+        arrived.sort_by_key(|(arrival, _)| *arrival);
+        arrived.into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    fn schedule(&mut self, conditions: &NetworkConditions, packet: T) {
+        let jitter = conditions.jitter.as_secs_f32();
+        let offset = (self.rng.f32() * 2.0 - 1.0) * jitter;
+        let mut delay = (conditions.latency.as_secs_f32() + offset).max(0.0);
+
+        if self.rng.f32() < conditions.reorder {
+            delay += REORDER_DELAY.as_secs_f32();
+        }
+
+        let arrival = Instant::now() + Duration::from_secs_f32(delay);
+        self.in_flight.push((arrival, packet));
+    }
+}
@@ -0,0 +1,310 @@
+use std::{env, fs};
+
+use bevy::prelude::warn;
+
+use crate::{game::BlastZone, hero::Hero};
+
+const DEFAULT_SERVER_URL: &str = "ws://192.168.2.170:3536";
+const DEFAULT_ROOM: &str = "next_2";
+const DEFAULT_NUM_PLAYERS: usize = 2;
+const DEFAULT_STOCKS: u32 = 3;
+
+pub const MIN_PLAYERS: usize = 2;
+pub const MAX_PLAYERS: usize = 4;
+
+// Check distance used by a bare `--synctest` flag
+const DEFAULT_CHECK_DISTANCE: usize = 2;
+
+// Fallback sources, lowest priority first: config file, then environment
+const CONFIG_FILE: &str = "dota_smash.cfg";
+const ENV_PREFIX: &str = "DOTA_SMASH_";
+const KEYS: [&str; 9] = [
+    "server",
+    "room",
+    "players",
+    "synctest",
+    "stocks",
+    "blast_zone",
+    "heroes",
+    "headless",
+    "network_seed",
+];
+
+// Keeps private rooms apart from matchmaking rooms named like a code
+const PRIVATE_ROOM_PREFIX: &str = "dota_smash_private_";
+// Passing this as room name generates a fresh room
+const GENERATED_ROOM: &str = "auto";
+const GENERATED_ROOM_PREFIX: &str = "dota_smash_";
+const GENERATED_ROOM_LEN: usize = 8;
+
+/// Runtime configuration of the game. Launchers build it from their own
+/// arguments (CLI for native, query string for WASM), everything they don't
+/// set falls back to the environment, `dota_smash.cfg` and the defaults.
+#[derive(Debug, Clone)]
+pub struct GameConfig {
+    /// Base url of the matchbox signaling server, e.g. `ws://127.0.0.1:3536`
+    pub server_url: String,
+    /// Matchbox room to join
+    pub room: String,
+    /// Number of players in a match, between `MIN_PLAYERS` and `MAX_PLAYERS`
+    pub num_players: usize,
+    /// Run a local GGRS sync test with this check distance instead of
+    /// connecting to peers
+    pub sync_test: Option<usize>,
+    /// Lives of every player at the start of a match
+    pub stocks: u32,
+    /// Players leaving these bounds lose a stock, set as
+    /// `left,right,bottom,top`
+    pub blast_zone: BlastZone,
+    /// Hero of every player handle in a sync test, e.g. `venomancer,axe`.
+    /// Handles without an entry play the hero picked in the hero select.
+    /// Online every player picks their own hero.
+    pub heroes: Vec<Hero>,
+    /// Simulate this many frames of a local match without a window, then
+    /// exit. Under a sync test if one is set. Native only.
+    pub headless: Option<u32>,
+    /// Seed of the simulated network conditions, shown in the debug window.
+    /// Random unless set.
+    pub network_seed: Option<u64>,
+}
+
+impl Default for GameConfig {
+    fn default() -> Self {
+        Self {
+            server_url: DEFAULT_SERVER_URL.to_string(),
+            room: DEFAULT_ROOM.to_string(),
+            num_players: DEFAULT_NUM_PLAYERS,
+            sync_test: None,
+            stocks: DEFAULT_STOCKS,
+            blast_zone: BlastZone::default(),
+            heroes: Vec::new(),
+            headless: None,
+            network_seed: None,
+        }
+    }
+}
+
+impl GameConfig {
+    /// Builds the config from launcher provided key/value pairs on top of the
+    /// config file and environment fallbacks.
+    pub fn load<I>(overrides: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        let mut config = Self::default();
+
+        if let Ok(contents) = fs::read_to_string(CONFIG_FILE) {
+            for (key, value) in parse_config_file(&contents) {
+                config.set(&key, &value);
+            }
+        }
+
+        for key in KEYS {
+            let var = ENV_PREFIX.to_string() + &key.to_uppercase();
+            if let Ok(value) = env::var(var) {
+                config.set(key, &value);
+            }
+        }
+
+        for (key, value) in overrides {
+            config.set(&key, &value);
+        }
+
+        if config.room == GENERATED_ROOM {
+            config.room = generate_room_name();
+        }
+
+        config
+    }
+
+    /// Native launcher: `--server=ws://host:3536 --room my_room`
+    pub fn from_args<I>(args: I) -> Self
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Self::load(parse_args(args))
+    }
+
+    /// WASM launcher: `?server=ws%3A%2F%2Fhost%3A3536&room=my_room`
+    pub fn from_query(query: &str) -> Self {
+        Self::load(parse_query(query))
+    }
+
+    /// Full url of the matchbox room the socket connects to. Matchbox groups
+    /// the next `num_players` peers of the room into one match.
+    pub fn room_url(&self) -> String {
+        format!(
+            "{}/{}?next={}",
+            self.server_url.trim_end_matches('/'),
+            self.room,
+            self.num_players
+        )
+    }
+
+    /// Full url of a private room. Matchbox connects everyone in it, the room
+    /// code is the only way in.
+    pub fn private_room_url(&self, code: &str) -> String {
+        format!(
+            "{}/{}{}",
+            self.server_url.trim_end_matches('/'),
+            PRIVATE_ROOM_PREFIX,
+            code
+        )
+    }
+
+    fn set(&mut self, key: &str, value: &str) {
+        match key {
+            "server" => self.server_url = value.to_string(),
+            "room" => self.room = value.to_string(),
+            "players" => match value.parse::<usize>() {
+                Ok(n) => self.num_players = n.clamp(MIN_PLAYERS, MAX_PLAYERS),
+                Err(_) => warn!("Invalid player count: {:?}", value),
+            },
+            "synctest" => match value {
+                "true" => self.sync_test = Some(DEFAULT_CHECK_DISTANCE),
+                "false" => self.sync_test = None,
+                _ => match value.parse::<usize>() {
+                    Ok(distance) => self.sync_test = Some(distance),
+                    Err(_) => warn!("Invalid check distance: {:?}", value),
+                },
+            },
+            "stocks" => match value.parse::<u32>() {
+                Ok(stocks) if stocks > 0 => self.stocks = stocks,
+                _ => warn!("Invalid stock count: {:?}", value),
+            },
+            "blast_zone" => match parse_blast_zone(value) {
+                Some(blast_zone) => self.blast_zone = blast_zone,
+                None => warn!("Invalid blast zone: {:?}", value),
+            },
+            "heroes" => {
+                let heroes: Option<Vec<Hero>> = value
+                    .split(',')
+                    .map(|name| Hero::from_name(name.trim()))
+                    .collect();
+
+                match heroes {
+                    Some(heroes) => self.heroes = heroes,
+                    None => warn!("Invalid heroes: {:?}", value),
+                }
+            }
+            "headless" => match value.parse::<u32>() {
+                Ok(frames) => self.headless = Some(frames),
+                Err(_) => warn!("Invalid frame count: {:?}", value),
+            },
+            "network_seed" => match value.parse::<u64>() {
+                Ok(seed) => self.network_seed = Some(seed),
+                Err(_) => warn!("Invalid network seed: {:?}", value),
+            },
+            _ => warn!("Unknown config key: {:?}", key),
+        }
+    }
+}
+
+fn generate_room_name() -> String {
+    let suffix: String = (0..GENERATED_ROOM_LEN)
+        .map(|_| fastrand::alphanumeric().to_ascii_lowercase())
+        .collect();
+
+    GENERATED_ROOM_PREFIX.to_string() + &suffix
+}
+
+fn parse_blast_zone(value: &str) -> Option<BlastZone> {
+    let bounds: Vec<f32> = value
+        .split(',')
+        .map(|bound| bound.trim().parse::<f32>())
+        .collect::<Result<_, _>>()
+        .ok()?;
+
+    match bounds[..] {
+        [left, right, bottom, top] if left < right && bottom < top => {
+            Some(BlastZone {
+                left,
+                right,
+                bottom,
+                top,
+            })
+        }
+        _ => None,
+    }
+}
+
+fn parse_config_file(contents: &str) -> Vec<(String, String)> {
+    contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| line.split_once('='))
+        .map(|(key, value)| (key.trim().to_string(), value.trim().to_string()))
+        .collect()
+}
+
+fn parse_args<I>(args: I) -> Vec<(String, String)>
+where
+    I: IntoIterator<Item = String>,
+{
+    let mut pairs = Vec::new();
+    let mut args = args.into_iter().peekable();
+
+    while let Some(arg) = args.next() {
+        let arg = match arg.strip_prefix("--") {
+            Some(arg) => arg.to_string(),
+            None => {
+                warn!("Ignoring argument: {:?}", arg);
+                continue;
+            }
+        };
+
+        if let Some((key, value)) = arg.split_once('=') {
+            pairs.push((key.to_string(), value.to_string()));
+            continue;
+        }
+
+        // `--key value`, or a bare flag if no value follows
+        match args.peek() {
+            Some(value) if !value.starts_with("--") => {
+                pairs.push((arg, args.next().unwrap()));
+            }
+            _ => pairs.push((arg, "true".to_string())),
+        }
+    }
+
+    pairs
+}
+
+fn parse_query(query: &str) -> Vec<(String, String)> {
+    query
+        .trim_start_matches('?')
+        .split('&')
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once('=') {
+            Some((key, value)) => (percent_decode(key), percent_decode(value)),
+            None => (percent_decode(pair), "true".to_string()),
+        })
+        .collect()
+}
+
+fn percent_decode(value: &str) -> String {
+    let bytes = value.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        match bytes[i] {
+            b'+' => decoded.push(b' '),
+            b'%' if i + 2 < bytes.len() => {
+                let hex = std::str::from_utf8(&bytes[i + 1..i + 3]).ok();
+                match hex.and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                    Some(byte) => {
+                        decoded.push(byte);
+                        i += 2;
+                    }
+                    None => decoded.push(b'%'),
+                }
+            }
+            byte => decoded.push(byte),
+        }
+        i += 1;
+    }
+
+    String::from_utf8_lossy(&decoded).to_string()
+}
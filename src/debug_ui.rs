@@ -1,7 +1,18 @@
+use std::time::Duration;
+
 use bevy::prelude::*;
-use bevy_egui::{egui::Window, EguiContext, EguiPlugin};
+use bevy_egui::{
+    egui::{Slider, Ui, Window},
+    EguiContext, EguiPlugin,
+};
 use ggrs::NetworkStats;
 
+use crate::conditions::{NetworkConditions, NetworkSimulator};
+
+// Upper ends of the network condition sliders
+const MAX_SIMULATED_LATENCY_MS: u64 = 500;
+const MAX_SIMULATED_JITTER_MS: u64 = 200;
+
 #[derive(Default, Clone)]
 pub struct Logger {
     log_lines: Vec<String>,
@@ -11,6 +22,10 @@ impl Logger {
     pub fn info(&mut self, msg: String) {
         self.log_lines.push("[INFO] ".to_string() + &msg);
     }
+
+    pub fn warn(&mut self, msg: String) {
+        self.log_lines.push("[WARN] ".to_string() + &msg);
+    }
 }
 
 pub struct DebugUiPlugin;
@@ -26,6 +41,7 @@ fn ui_example(
     mut egui_context: ResMut<EguiContext>,
     logger: Res<Logger>,
     network_status: Option<Res<NetworkStats>>,
+    simulator: Option<Res<NetworkSimulator>>,
 ) {
     Window::new("Debug").show(egui_context.ctx_mut(), |ui| {
         for log_line in logger.log_lines.iter().rev().take(5) {
@@ -47,5 +63,58 @@ fn ui_example(
 
             ui.label("ping: ".to_string() + &network_status.ping.to_string());
         }
+
+        if let Some(simulator) = simulator {
+            ui.collapsing("Network conditions", |ui| {
+                network_conditions(ui, &simulator);
+            });
+        }
     });
 }
+
+/// Sliders for the conditions the session socket simulates, applied live.
+fn network_conditions(ui: &mut Ui, simulator: &NetworkSimulator) {
+    let mut conditions = simulator.conditions();
+
+    // Peers simulate their own side, the same conditions on every peer make
+    // a symmetric connection
+    ui.label("Applied to the packets we send");
+    ui.label("seed: ".to_string() + &simulator.seed().to_string());
+
+    let mut latency = conditions.latency.as_millis() as u64;
+    let mut jitter = conditions.jitter.as_millis() as u64;
+    ui.add(
+        Slider::new(&mut latency, 0..=MAX_SIMULATED_LATENCY_MS)
+            .text("latency")
+            .suffix(" ms"),
+    );
+    ui.add(
+        Slider::new(&mut jitter, 0..=MAX_SIMULATED_JITTER_MS)
+            .text("jitter")
+            .suffix(" ms"),
+    );
+    conditions.latency = Duration::from_millis(latency);
+    conditions.jitter = Duration::from_millis(jitter);
+
+    for (chance, name) in [
+        (&mut conditions.loss, "loss"),
+        (&mut conditions.duplicate, "duplication"),
+        (&mut conditions.reorder, "reordering"),
+    ] {
+        let mut percent = *chance * 100.0;
+        let slider = Slider::new(&mut percent, 0.0..=100.0)
+            .text(name)
+            .suffix(" %");
+        if ui.add(slider).changed() {
+            *chance = percent / 100.0;
+        }
+    }
+
+    if ui.button("Perfect network").clicked() {
+        conditions = NetworkConditions::default();
+    }
+
+    if conditions != simulator.conditions() {
+        simulator.set_conditions(conditions);
+    }
+}
@@ -0,0 +1,659 @@
+use std::{
+    mem,
+    ops::RangeInclusive,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::{warn, Res, ResMut, Time};
+use ggrs::{Message, NonBlockingSocket, P2PSession};
+
+use crate::{
+    checksum::{ChecksumHistory, CHECKSUM_EXCHANGE_INTERVAL},
+    conditions::{DelayLine, NetworkSimulator},
+    debug_ui::Logger,
+    net::{self, FrameCount, GGRSConfig, PeerSocket},
+};
+
+// Apart from the lobby message tags, so stray lobby messages aren't mistaken
+// for session packets. Tags below are lobby messages.
+pub const PACKET_GGRS: u8 = 0x80;
+const PACKET_CHECKSUM: u8 = 0x81;
+const PACKET_STATE_DUMP: u8 = 0x82;
+const PACKET_DUMP_ACK: u8 = 0x83;
+/// Confirmed inputs for a spectator that joined the running match
+pub const PACKET_LATE_JOIN_INPUTS: u8 = 0x84;
+/// Frames a late spectator received so far, also how it asks to join
+pub const PACKET_LATE_JOIN_ACK: u8 = 0x85;
+// Session packets of other builds aren't queued for anyone
+const LAST_SESSION_PACKET: u8 = PACKET_LATE_JOIN_ACK;
+
+// State dumps run into hundreds of kilobytes, far above what fits a
+// datagram. They go in chunks of this size, each resent until acked.
+const DUMP_CHUNK_BYTES: usize = 8 * 1024;
+const DUMP_RESEND_SECONDS: f32 = 0.5;
+
+#[cfg(not(target_arch = "wasm32"))]
+const DESYNC_DIR: &str = "desyncs";
+
+/// Packets we exchange next to GGRS during a session.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum SessionPacket {
+    /// Checksum of the state at the end of a confirmed frame
+    Checksum { frame: u32, value: u64 },
+    /// Chunk `index` out of `count` of the readable state of a frame that
+    /// desynced, for diffing against ours
+    StateDump {
+        frame: u32,
+        index: u16,
+        count: u16,
+        chunk: Vec<u8>,
+    },
+    /// Chunk `index` of the state dump of `frame` arrived
+    DumpAck { frame: u32, index: u16 },
+}
+
+impl SessionPacket {
+    fn encode(&self) -> Box<[u8]> {
+        let mut packet = Vec::new();
+        match self {
+            SessionPacket::Checksum { frame, value } => {
+                packet.push(PACKET_CHECKSUM);
+                packet.extend(frame.to_le_bytes());
+                packet.extend(value.to_le_bytes());
+            }
+            SessionPacket::StateDump {
+                frame,
+                index,
+                count,
+                chunk,
+            } => {
+                packet.push(PACKET_STATE_DUMP);
+                packet.extend(frame.to_le_bytes());
+                packet.extend(index.to_le_bytes());
+                packet.extend(count.to_le_bytes());
+                packet.extend(chunk);
+            }
+            SessionPacket::DumpAck { frame, index } => {
+                packet.push(PACKET_DUMP_ACK);
+                packet.extend(frame.to_le_bytes());
+                packet.extend(index.to_le_bytes());
+            }
+        }
+        packet.into_boxed_slice()
+    }
+
+    fn decode(bytes: &[u8]) -> Option<SessionPacket> {
+        let (&tag, body) = bytes.split_first()?;
+        let frame = u32::from_le_bytes(body.get(..4)?.try_into().ok()?);
+        let rest = &body[4..];
+
+        match tag {
+            PACKET_CHECKSUM => Some(SessionPacket::Checksum {
+                frame,
+                value: u64::from_le_bytes(rest.try_into().ok()?),
+            }),
+            PACKET_STATE_DUMP => {
+                let index = u16::from_le_bytes(rest.get(..2)?.try_into().ok()?);
+                let count =
+                    u16::from_le_bytes(rest.get(2..4)?.try_into().ok()?);
+                if index >= count {
+                    return None;
+                }
+
+                Some(SessionPacket::StateDump {
+                    frame,
+                    index,
+                    count,
+                    chunk: rest[4..].to_vec(),
+                })
+            }
+            PACKET_DUMP_ACK => Some(SessionPacket::DumpAck {
+                frame,
+                index: u16::from_le_bytes(rest.try_into().ok()?),
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Our packets on their way to and from the socket the session owns.
+#[derive(Default)]
+struct PacketQueues {
+    outgoing: Vec<(String, Box<[u8]>)>,
+    incoming: Vec<(String, Box<[u8]>)>,
+}
+
+/// Our own packets next to the ones of GGRS, on the socket the session owns.
+/// The socket flushes them whenever GGRS polls it. Clones share the queues.
+#[derive(Clone, Default)]
+pub struct SessionChannel {
+    queues: Arc<Mutex<PacketQueues>>,
+}
+
+impl SessionChannel {
+    pub fn send(&self, peer: &str, packet: Box<[u8]>) {
+        let mut queues = self.queues.lock().unwrap();
+        queues.outgoing.push((peer.to_string(), packet));
+    }
+
+    /// Packets received with a tag in `tags`. The others stay queued for
+    /// whoever reads them.
+    pub fn receive(
+        &self,
+        tags: RangeInclusive<u8>,
+    ) -> Vec<(String, Box<[u8]>)> {
+        let mut queues = self.queues.lock().unwrap();
+        let (matching, others) = mem::take(&mut queues.incoming)
+            .into_iter()
+            .partition(|(_, packet)| {
+                packet.first().map_or(false, |tag| tags.contains(tag))
+            });
+        queues.incoming = others;
+        matching
+    }
+}
+
+/// The socket of the room, once handed to GGRS. Tags every packet, so our own
+/// packets share the connection with the ones of GGRS. Packets we send are
+/// delayed, lost, duplicated and reordered as the simulator says, the ones we
+/// receive went through the simulator of their sender.
+pub struct SessionSocket {
+    socket: PeerSocket,
+    channel: SessionChannel,
+    simulator: NetworkSimulator,
+    outgoing: DelayLine<(String, Box<[u8]>)>,
+}
+
+impl SessionSocket {
+    pub fn new(
+        socket: PeerSocket,
+        channel: &SessionChannel,
+        simulator: &NetworkSimulator,
+    ) -> Self {
+        Self {
+            socket,
+            channel: channel.clone(),
+            simulator: simulator.clone(),
+            outgoing: DelayLine::with_seed(simulator.seed()),
+        }
+    }
+
+    fn send(&mut self, peer: String, packet: Box<[u8]>) {
+        let conditions = self.simulator.conditions();
+        if conditions.is_perfect() {
+            self.socket.send(packet, peer);
+        } else {
+            self.outgoing.send(&conditions, (peer, packet));
+        }
+    }
+
+    /// Sends what made it through the simulated network so far, and
+    /// receives.
+    fn receive(&mut self) -> Vec<(String, Box<[u8]>)> {
+        for (peer, packet) in self.outgoing.receive() {
+            self.socket.send(packet, peer);
+        }
+
+        // Spectators joining the running match connect to the socket of the
+        // session, their packets are received either way
+        self.socket.accept_new_connections();
+
+        self.socket.receive()
+    }
+}
+
+impl NonBlockingSocket<String> for SessionSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        let mut packet = vec![PACKET_GGRS];
+        match bincode::serialize(msg) {
+            Ok(bytes) => packet.extend(bytes),
+            Err(e) => {
+                warn!("Failed to serialize GGRS message: {}", e);
+                return;
+            }
+        }
+
+        self.send(addr.clone(), packet.into_boxed_slice());
+    }
+
+    /// GGRS polls this every frame, our packets are flushed along.
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        let outgoing =
+            mem::take(&mut self.channel.queues.lock().unwrap().outgoing);
+        for (peer, packet) in outgoing {
+            self.send(peer, packet);
+        }
+
+        let mut messages = Vec::new();
+        for (peer, packet) in self.receive() {
+            let body = match packet.split_first() {
+                Some((&PACKET_GGRS, body)) => body,
+                // Resent by a peer that didn't see us start yet
+                Some((&tag, _)) if tag < PACKET_GGRS => continue,
+                Some((&tag, _)) if tag <= LAST_SESSION_PACKET => {
+                    let mut queues = self.channel.queues.lock().unwrap();
+                    queues.incoming.push((peer, packet));
+                    continue;
+                }
+                Some(_) => {
+                    warn!("Unknown session packet from {}", peer);
+                    continue;
+                }
+                None => continue,
+            };
+
+            match bincode::deserialize(body) {
+                Ok(msg) => messages.push((peer, msg)),
+                Err(_) => warn!("Invalid GGRS packet from {}", peer),
+            }
+        }
+
+        messages
+    }
+}
+
+/// Compares checksums of confirmed frames with the peers. The first frame
+/// that differs is logged and the states of both sides are written to
+/// `desyncs/` for diffing.
+#[derive(Default)]
+pub struct DesyncDetector {
+    channel: SessionChannel,
+    /// Remote players of the session
+    peers: Vec<String>,
+    /// Last frame we sent the checksum of
+    last_sent: u32,
+    /// Checksums of the peers for frames we haven't confirmed yet
+    remote: Vec<(String, u32, u64)>,
+    /// Our state dumps the peers haven't fully acked yet
+    outgoing_dumps: Vec<OutgoingDump>,
+    /// State dumps of the peers, by the chunks that arrived so far
+    incoming_dumps: Vec<IncomingDump>,
+    /// The first frame found to differ, desyncs are reported once
+    pub desynced_at: Option<u32>,
+}
+
+struct OutgoingDump {
+    peer: String,
+    frame: u32,
+    chunks: Vec<Vec<u8>>,
+    acked: Vec<bool>,
+    /// Seconds since the chunks not acked yet were last sent
+    since_sent: f32,
+}
+
+struct IncomingDump {
+    peer: String,
+    frame: u32,
+    chunks: Vec<Option<Vec<u8>>>,
+    /// Saved once every chunk arrived, later resends are only acked
+    saved: bool,
+}
+
+impl DesyncDetector {
+    /// Starts over for a new session, comparing with the given peers.
+    pub fn start(&mut self, peers: Vec<String>, channel: &SessionChannel) {
+        *self = DesyncDetector {
+            channel: channel.clone(),
+            peers,
+            ..Default::default()
+        };
+    }
+
+    fn send(&self, peer: &str, packet: &SessionPacket) {
+        self.channel.send(peer, packet.encode());
+    }
+
+    /// Starts sending `dump` to `peer`, see `resend_dumps`.
+    fn send_dump(&mut self, peer: &str, frame: u32, dump: &str) {
+        let mut chunks: Vec<_> = dump
+            .as_bytes()
+            .chunks(DUMP_CHUNK_BYTES)
+            .map(|chunk| chunk.to_vec())
+            .collect();
+        if chunks.is_empty() {
+            chunks.push(Vec::new());
+        }
+
+        if chunks.len() > u16::MAX as usize {
+            warn!("State dump of frame {} too large to send", frame);
+            return;
+        }
+
+        self.outgoing_dumps.push(OutgoingDump {
+            peer: peer.to_string(),
+            frame,
+            acked: vec![false; chunks.len()],
+            chunks,
+            // Sent right away
+            since_sent: DUMP_RESEND_SECONDS,
+        });
+    }
+
+    /// Sends the chunks of our dumps that weren't acked yet, again every
+    /// `DUMP_RESEND_SECONDS` as packets may be lost.
+    fn resend_dumps(&mut self, delta: f32) {
+        let mut packets = Vec::new();
+        for dump in self.outgoing_dumps.iter_mut() {
+            dump.since_sent += delta;
+            if dump.since_sent < DUMP_RESEND_SECONDS {
+                continue;
+            }
+            dump.since_sent = 0.0;
+
+            let count = dump.chunks.len() as u16;
+            for (index, chunk) in dump.chunks.iter().enumerate() {
+                if dump.acked[index] {
+                    continue;
+                }
+
+                let packet = SessionPacket::StateDump {
+                    frame: dump.frame,
+                    index: index as u16,
+                    count,
+                    chunk: chunk.clone(),
+                };
+                packets.push((dump.peer.clone(), packet));
+            }
+        }
+
+        for (peer, packet) in packets {
+            self.send(&peer, &packet);
+        }
+    }
+
+    fn ack_dump(&mut self, peer: &str, frame: u32, index: u16) {
+        let dump = self
+            .outgoing_dumps
+            .iter_mut()
+            .find(|dump| dump.peer == peer && dump.frame == frame);
+
+        if let Some(acked) =
+            dump.and_then(|dump| dump.acked.get_mut(index as usize))
+        {
+            *acked = true;
+        }
+
+        self.outgoing_dumps
+            .retain(|dump| dump.acked.iter().any(|acked| !acked));
+    }
+
+    /// Adds a chunk of a peer's dump, returns the whole dump once the last
+    /// chunk arrived.
+    fn receive_dump(
+        &mut self,
+        peer: &str,
+        frame: u32,
+        index: u16,
+        count: u16,
+        chunk: Vec<u8>,
+    ) -> Option<String> {
+        self.send(peer, &SessionPacket::DumpAck { frame, index });
+
+        let position = self
+            .incoming_dumps
+            .iter()
+            .position(|dump| dump.peer == peer && dump.frame == frame);
+        let dump = match position {
+            Some(position) => &mut self.incoming_dumps[position],
+            None => {
+                self.incoming_dumps.push(IncomingDump {
+                    peer: peer.to_string(),
+                    frame,
+                    chunks: vec![None; count as usize],
+                    saved: false,
+                });
+                self.incoming_dumps.last_mut().unwrap()
+            }
+        };
+
+        if dump.saved || dump.chunks.len() != count as usize {
+            return None;
+        }
+        dump.chunks[index as usize] = Some(chunk);
+
+        if dump.chunks.iter().any(Option::is_none) {
+            return None;
+        }
+        dump.saved = true;
+
+        let bytes: Vec<u8> =
+            dump.chunks.iter().flatten().flatten().copied().collect();
+        Some(String::from_utf8_lossy(&bytes).to_string())
+    }
+}
+
+pub fn exchange_checksums(
+    time: Res<Time>,
+    frame_count: Res<FrameCount>,
+    session: Option<Res<P2PSession<GGRSConfig>>>,
+    history: Res<ChecksumHistory>,
+    mut detector: ResMut<DesyncDetector>,
+    mut logger: ResMut<Logger>,
+) {
+    if detector.peers.is_empty() {
+        return;
+    }
+
+    let confirmed = net::confirmed_frames(session.as_deref(), &frame_count);
+
+    let mut frame = detector.last_sent + CHECKSUM_EXCHANGE_INTERVAL;
+    while frame <= confirmed {
+        if let Some(value) = history.get(frame) {
+            let packet = SessionPacket::Checksum { frame, value };
+            for peer in detector.peers.iter() {
+                detector.send(peer, &packet);
+            }
+        }
+        detector.last_sent = frame;
+        frame += CHECKSUM_EXCHANGE_INTERVAL;
+    }
+
+    let incoming = detector.channel.receive(PACKET_CHECKSUM..=PACKET_DUMP_ACK);
+    for (peer, packet) in incoming {
+        match SessionPacket::decode(&packet) {
+            Some(SessionPacket::Checksum { frame, value }) => {
+                detector.remote.push((peer, frame, value));
+            }
+            Some(SessionPacket::StateDump {
+                frame,
+                index,
+                count,
+                chunk,
+            }) => {
+                if let Some(dump) =
+                    detector.receive_dump(&peer, frame, index, count, chunk)
+                {
+                    let name: String = peer.chars().take(6).collect();
+                    save_dump(frame, &name, &dump, &mut logger);
+                }
+            }
+            Some(SessionPacket::DumpAck { frame, index }) => {
+                detector.ack_dump(&peer, frame, index);
+            }
+            None => warn!("Invalid session packet from {}", peer),
+        }
+    }
+
+    let mut pending = Vec::new();
+    for (peer, frame, remote) in mem::take(&mut detector.remote) {
+        if frame > confirmed {
+            pending.push((peer, frame, remote));
+            continue;
+        }
+
+        // Too old to still be in the history otherwise
+        let local = match history.get(frame) {
+            Some(local) => local,
+            None => continue,
+        };
+
+        if local == remote || detector.desynced_at.is_some() {
+            continue;
+        }
+
+        logger.warn(format!(
+            "Desync with {} at frame {}: checksum {:016x}, theirs {:016x}",
+            peer, frame, local, remote
+        ));
+        detector.desynced_at = Some(frame);
+
+        // The peer noticed too and sends us its state the same way
+        if let Some(dump) = history.dump(frame) {
+            save_dump(frame, "local", dump, &mut logger);
+            detector.send_dump(&peer, frame, dump);
+        }
+    }
+    detector.remote = pending;
+
+    detector.resend_dumps(time.delta_seconds());
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+fn save_dump(frame: u32, side: &str, dump: &str, logger: &mut Logger) {
+    let name = format!("frame_{}_{}.txt", frame, side);
+    let path = std::path::Path::new(DESYNC_DIR).join(&name);
+    let result = std::fs::create_dir_all(DESYNC_DIR)
+        .and_then(|_| std::fs::write(&path, dump));
+
+    match result {
+        Ok(_) => logger.info(format!("State dumped to {}", path.display())),
+        Err(e) => logger.warn(format!("Failed to write {}: {}", name, e)),
+    }
+}
+
+/// Browsers have no desyncs directory, the state goes to the console.
+#[cfg(target_arch = "wasm32")]
+fn save_dump(frame: u32, side: &str, dump: &str, logger: &mut Logger) {
+    logger.info(format!("State of frame {} logged", frame));
+    bevy::prelude::info!("State of frame {} ({}):\n{}", frame, side, dump);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(packet: SessionPacket) {
+        assert_eq!(SessionPacket::decode(&packet.encode()), Some(packet));
+    }
+
+    #[test]
+    fn packets_round_trip() {
+        round_trip(SessionPacket::Checksum {
+            frame: 120,
+            value: 0xdead_beef_cafe_f00d,
+        });
+        round_trip(SessionPacket::StateDump {
+            frame: 7,
+            index: 2,
+            count: 3,
+            chunk: b"Player { handle: 0 }".to_vec(),
+        });
+        round_trip(SessionPacket::StateDump {
+            frame: 7,
+            index: 0,
+            count: 1,
+            chunk: Vec::new(),
+        });
+        round_trip(SessionPacket::DumpAck { frame: 7, index: 2 });
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let packets = [
+            SessionPacket::Checksum { frame: 1, value: 2 },
+            SessionPacket::DumpAck { frame: 1, index: 2 },
+        ];
+
+        for packet in packets {
+            let bytes = packet.encode();
+            for len in 0..bytes.len() {
+                assert_eq!(SessionPacket::decode(&bytes[..len]), None);
+            }
+        }
+
+        // Everything up to the chunk is required, the chunk may be empty
+        let dump = SessionPacket::StateDump {
+            frame: 1,
+            index: 0,
+            count: 1,
+            chunk: Vec::new(),
+        }
+        .encode();
+        for len in 0..dump.len() {
+            assert_eq!(SessionPacket::decode(&dump[..len]), None);
+        }
+    }
+
+    #[test]
+    fn invalid_packets_are_rejected() {
+        // Unknown tag
+        assert_eq!(SessionPacket::decode(&[0xff, 0, 0, 0, 0]), None);
+        // GGRS packets aren't ours to decode
+        assert_eq!(SessionPacket::decode(&[PACKET_GGRS, 0, 0, 0, 0]), None);
+
+        // Trailing bytes after a checksum
+        let mut checksum = SessionPacket::Checksum { frame: 1, value: 2 }
+            .encode()
+            .to_vec();
+        checksum.push(0);
+        assert_eq!(SessionPacket::decode(&checksum), None);
+
+        // Chunk index out of range
+        let dump = SessionPacket::StateDump {
+            frame: 1,
+            index: 1,
+            count: 1,
+            chunk: vec![1, 2, 3],
+        };
+        assert_eq!(SessionPacket::decode(&dump.encode()), None);
+    }
+
+    #[test]
+    fn state_dumps_are_reassembled_from_chunks() {
+        let mut sender = DesyncDetector::default();
+        let mut receiver = DesyncDetector::default();
+        let dump = "x".repeat(DUMP_CHUNK_BYTES * 2 + 1);
+        sender.send_dump("receiver", 5, &dump);
+        sender.resend_dumps(0.0);
+
+        let mut packets: Vec<_> =
+            mem::take(&mut sender.channel.queues.lock().unwrap().outgoing)
+                .into_iter()
+                .filter_map(|(_, packet)| SessionPacket::decode(&packet))
+                .collect();
+        assert_eq!(packets.len(), 3);
+
+        // Out of order, with one chunk arriving twice
+        packets.reverse();
+        packets.push(packets[0].clone());
+
+        let mut received = Vec::new();
+        for packet in packets {
+            if let SessionPacket::StateDump {
+                frame,
+                index,
+                count,
+                chunk,
+            } = packet
+            {
+                received.extend(
+                    receiver.receive_dump("sender", frame, index, count, chunk),
+                );
+            }
+        }
+        assert_eq!(received, vec![dump]);
+
+        // Every chunk is acked, the sender stops resending
+        let acks =
+            mem::take(&mut receiver.channel.queues.lock().unwrap().outgoing);
+        assert_eq!(acks.len(), 4);
+        for (_, packet) in acks {
+            if let Some(SessionPacket::DumpAck { frame, index }) =
+                SessionPacket::decode(&packet)
+            {
+                sender.ack_dump("receiver", frame, index);
+            }
+        }
+        assert!(sender.outgoing_dumps.is_empty());
+    }
+}
@@ -7,27 +7,113 @@ use bevy_ggrs::RollbackIdProvider;
 use bevy_rapier2d::parry::query::details::intersection_test_aabb_segment;
 use bevy_rapier2d::parry::query::intersection_test;
 use bevy_rapier2d::prelude::*;
+use ggrs::P2PSession;
+use serde::{Deserialize, Serialize};
 
+use crate::combat::{apply_hit, Attack};
+use crate::config::GameConfig;
+use crate::debug_ui::Logger;
+use crate::desync;
+use crate::hero::{Hero, HeroDefinition, HeroDefinitions, Hurtbox, Projectile};
+use crate::late_join;
+use crate::lobby::{self, LobbySystem};
 use crate::net;
 use crate::player;
 use crate::player::Player;
+use crate::replay;
 
 pub const FPS: f32 = 60.0;
+pub const CAMERA_SCALE: f32 = 2.0;
 pub const ROLLBACK_DEFAULT: &str = "rollback_default";
 
-#[derive(PartialEq, Debug)]
+/// Simulated time per rollback frame, timers in the rollback schedule tick by
+/// this instead of the wall clock so every peer advances them identically.
+pub const FRAME_DURATION: Duration =
+    Duration::from_nanos((1_000_000_000.0 / FPS) as u64);
+
+// Frames a respawned player can't be hit
+const RESPAWN_INVULNERABILITY: u32 = 120;
+
+// Where eliminated players are kept until the match ends
+const ELIMINATED_POSITION: Vec3 = Vec3::new(0.0, 10_000.0, 1.0);
+
+/// App states, systems of a stage are registered as enter/update/exit sets.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
 pub enum GameStage {
-    SetupLobby,
-    SetupLobbyPlayer,
-    SetupSocket,
-    SetupSession,
-    SetupGameplayPlayers,
+    MainMenu,
+    /// Quick match, create or join a private room
+    RoomSelect,
+    /// Control bindings
+    Settings,
+    HeroSelect,
+    /// Local player runs around while we wait for the session to start
+    Lobby,
     Gameplay,
+    /// Results of the finished match
+    MatchEnd,
+    /// Passes straight back into the lobby to queue for the next match
+    Rematch,
+    /// Saved replays to pick from
+    ReplayBrowser,
+    /// Simulates a replay with playback controls
+    ReplayViewer,
 }
 
-#[derive(Debug)]
-pub struct GameState {
-    pub stage: GameStage,
+/// Bounds around the stage, players outside of them are KO'd.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize)]
+pub struct BlastZone {
+    pub left: f32,
+    pub right: f32,
+    pub bottom: f32,
+    pub top: f32,
+}
+
+impl Default for BlastZone {
+    fn default() -> Self {
+        Self {
+            left: -1600.0,
+            right: 1600.0,
+            bottom: -1100.0,
+            top: 1300.0,
+        }
+    }
+}
+
+impl BlastZone {
+    pub fn contains(&self, position: Vec3) -> bool {
+        position.x > self.left
+            && position.x < self.right
+            && position.y > self.bottom
+            && position.y < self.top
+    }
+}
+
+/// Rollback state of the match as a whole, set once at most one player has
+/// stocks left.
+#[derive(Default, Reflect, Hash, Component, Debug)]
+#[reflect(Hash)]
+pub struct MatchOutcome {
+    pub finished: bool,
+    /// Frame count at the end of the frame that finished the match
+    pub finished_at: u32,
+    /// Handle of the last player standing, only valid if `has_winner`
+    pub winner: usize,
+    pub has_winner: bool,
+}
+
+/// What kind of match the main menu started.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum MatchMode {
+    /// Against peers over matchbox
+    #[default]
+    Online,
+    /// Up to `config::MAX_PLAYERS` players on one machine
+    LocalVersus,
+    /// Alone against an idle training dummy, no network involved
+    Training,
+    /// Watching a match of a private room, inputs come from one of its
+    /// players. Joins the running match if there is one, the next otherwise.
+    Spectate,
 }
 
 pub struct GamePlugin;
@@ -36,42 +122,91 @@ impl Plugin for GamePlugin {
     fn build(&self, app: &mut bevy::prelude::App) {
         net::setup_ggrs(app);
 
-        app.insert_resource(GameState {
-            stage: GameStage::SetupLobby,
-        })
-        .add_system(setup_lobby)
-        .add_system(player::setup_lobby_player)
-        .add_system(player::local_input_system)
-        .add_system(player::animate_players)
-        .add_system(net::setup_socket)
-        .add_system(net::setup_session)
-        .add_system(player::setup_gameplay_players)
-        .add_system(fireball_system)
-        .add_system(animate_fireball_system);
-    }
-}
-
-fn setup_lobby(
-    commands: Commands,
-    asset_server: ResMut<AssetServer>,
-    mut game_state: ResMut<GameState>,
-) {
-    if game_state.stage != GameStage::SetupLobby {
-        return;
+        app.add_state(GameStage::MainMenu)
+            .insert_resource(MatchOutcome::default())
+            .insert_resource(MatchMode::default())
+            .add_startup_system(setup_world)
+            .add_system(player::animate_players)
+            .add_system_set(
+                SystemSet::on_enter(GameStage::Lobby)
+                    .with_system(player::setup_lobby_player)
+                    .with_system(lobby::reset_lobby_state)
+                    .with_system(net::setup_socket),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameStage::Lobby)
+                    .with_system(player::local_input_system)
+                    .with_system(
+                        lobby::exchange_lobby_messages
+                            .label(LobbySystem::ExchangeMessages),
+                    )
+                    .with_system(late_join::request_running_match)
+                    .with_system(
+                        late_join::join_running_match
+                            .label(LobbySystem::JoinRunningMatch)
+                            .after(LobbySystem::ExchangeMessages),
+                    )
+                    // Sees the ready flag only once it was sent, and no
+                    // socket once a running match was joined
+                    .with_system(
+                        net::setup_session
+                            .after(LobbySystem::ExchangeMessages)
+                            .after(LobbySystem::JoinRunningMatch),
+                    )
+                    .with_system(fireball_system)
+                    .with_system(animate_fireball_system),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameStage::Lobby)
+                    .with_system(despawn_match_entities)
+                    .with_system(net::close_socket),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameStage::Gameplay)
+                    .with_system(net::reset_rollback_state)
+                    .with_system(player::setup_gameplay_players)
+                    .with_system(replay::start_recording),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameStage::Gameplay)
+                    .with_system(end_match)
+                    .with_system(desync::exchange_checksums)
+                    .with_system(late_join::stream_to_late_spectators)
+                    .with_system(late_join::follow_match.exclusive_system())
+                    .with_system(net::return_to_lobby_on_disconnect),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameStage::Gameplay)
+                    .with_system(despawn_match_entities)
+                    .with_system(net::end_session)
+                    .with_system(late_join::leave_live_match),
+            )
+            .add_system_set(
+                SystemSet::on_enter(GameStage::Rematch).with_system(rematch),
+            )
+            // Seeking backwards restarts this stage
+            .add_system_set(
+                SystemSet::on_enter(GameStage::ReplayViewer)
+                    .with_system(net::reset_rollback_state)
+                    .with_system(player::setup_gameplay_players),
+            )
+            .add_system_set(
+                SystemSet::on_update(GameStage::ReplayViewer)
+                    .with_system(replay::play_replay.exclusive_system()),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameStage::ReplayViewer)
+                    .with_system(despawn_match_entities),
+            );
     }
-
-    // setup camera, background & platform
-    setup_world(commands, asset_server);
-
-    // Lobby is set up transition to next game stage
-    game_state.stage = GameStage::SetupLobbyPlayer;
 }
 
+/// Camera, background & platform, shared by all stages
 fn setup_world(mut commands: Commands, asset_server: ResMut<AssetServer>) {
     // Camera
     commands.spawn_bundle(Camera2dBundle {
         projection: OrthographicProjection {
-            scale: 2.0,
+            scale: CAMERA_SCALE,
             ..Default::default()
         },
         ..Default::default()
@@ -106,49 +241,47 @@ fn setup_world(mut commands: Commands, asset_server: ResMut<AssetServer>) {
         ));
 }
 
-#[derive(Component, Reflect, Default)]
+#[derive(Component, Reflect, Default, Debug)]
 pub struct Fireball {
-    player_handle: usize,
+    pub player_handle: usize,
 }
 
-#[derive(Component)]
-pub struct FireballTimer(Timer);
-
-#[derive(Component)]
-pub struct FireballLiveTimer(Timer);
+#[derive(Component, Reflect, Default, Debug)]
+pub struct FireballLiveTimer(pub Timer);
 
-#[derive(Component)]
-pub struct FireballAnimationTimer(Timer);
+#[derive(Component, Reflect, Default)]
+pub struct FireballAnimationTimer(pub Timer);
 
 pub fn spawn_fireball(
     commands: &mut Commands,
-    mut texture_atlases: &mut ResMut<Assets<TextureAtlas>>,
-    player_entity: (&Player, &Transform, &TextureAtlasSprite),
+    texture_atlases: &mut ResMut<Assets<TextureAtlas>>,
+    player_entity: (&Player, &Transform),
+    projectile: &Projectile,
+    hurtbox: &Hurtbox,
     asset_server: &AssetServer,
     rip: &mut RollbackIdProvider,
-    fireball_query: &Query<(&Fireball, &FireballTimer)>,
 ) {
-    for (f, ft) in fireball_query {
-        if f.player_handle == player_entity.0.handle {
-            if !ft.0.finished() {
-                return; // Any fireball fired recently? Don't fire a new one...
-            }
-        }
-    }
-
-    let texture_handle = asset_server.load("fireball.png");
-    let texture_atlas =
-        TextureAtlas::from_grid(texture_handle, Vec2::new(25.0, 25.0), 6, 1);
+    let sprite = &projectile.sprite;
+    let texture_handle = asset_server.load(sprite.path.as_str());
+    let texture_atlas = TextureAtlas::from_grid(
+        texture_handle,
+        sprite.tile_size,
+        sprite.columns,
+        sprite.rows,
+    );
     let texture_atlas_handle = texture_atlases.add(texture_atlas);
 
+    // Out of the body of the caster, at the height hurtboxes are hit at
     let mut transform = player_entity.1.clone();
+    let center = player::hurtbox_center(player_entity.1, hurtbox);
+    transform.translation = center.extend(transform.translation.z);
     let mut vel = Velocity::default();
-    if player_entity.2.flip_x {
-        vel.linvel.x = -30.0;
-        transform.translation.x -= 50.0;
+    if player_entity.0.facing_left {
+        vel.linvel.x = -projectile.speed;
+        transform.translation.x -= projectile.spawn_offset;
     } else {
-        vel.linvel.x = 30.0;
-        transform.translation.x += 50.0;
+        vel.linvel.x = projectile.speed;
+        transform.translation.x += projectile.spawn_offset;
     }
 
     commands
@@ -160,15 +293,20 @@ pub fn spawn_fireball(
             player_handle: player_entity.0.handle,
             ..Default::default()
         })
-        .insert(FireballAnimationTimer(Timer::from_seconds(0.05, true)))
-        .insert(FireballTimer(Timer::from_seconds(0.2, false)))
-        .insert(FireballLiveTimer(Timer::from_seconds(3.0, false)))
+        .insert(FireballAnimationTimer(Timer::from_seconds(
+            sprite.frame_seconds,
+            true,
+        )))
+        .insert(FireballLiveTimer(Timer::from_seconds(
+            projectile.lifetime_seconds,
+            false,
+        )))
         .insert(transform)
         .insert(vel)
         .insert(Rollback::new(rip.next_id()));
 }
 
-fn fireball_system(
+pub fn fireball_system(
     mut commands: Commands,
     mut query: Query<
         (
@@ -176,46 +314,91 @@ fn fireball_system(
             &Fireball,
             &Velocity,
             &mut Transform,
-            &mut FireballTimer,
             &mut FireballLiveTimer,
         ),
         Without<Player>,
     >,
     mut player_query: Query<
-        (&Player, &Velocity, &mut Transform),
+        (Entity, &mut Player, &Hero, &mut Velocity, &Transform),
         Without<Fireball>,
     >,
-    time: Res<Time>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
 ) {
-    for (e, f, v, mut t, mut ft, mut flt) in query.iter_mut() {
-        for (p, p_v, mut p_t) in player_query.iter_mut() {
-            if p.handle != f.player_handle {
-                let collision = bevy::sprite::collide_aabb::collide(
-                    t.translation,
-                    Vec2::new(25.0, 25.0),
-                    p_t.translation,
-                    Vec2::new(100.0, 100.0),
-                );
-
-                if collision.is_some() {
-                    p_t.translation.x += v.linvel.x;
-                    commands.entity(e).despawn();
-                }
+    // Projectile stats of every caster, by player handle
+    let projectiles: Vec<(usize, &Projectile)> = player_query
+        .iter()
+        .filter_map(|(_, p, hero, ..)| {
+            let def = definitions.get(*hero, &hero_assets)?;
+            Some((p.handle, def.projectile()?))
+        })
+        .collect();
+
+    // Hits of this frame, applied once all fireballs moved
+    let mut hits: Vec<(usize, Entity, &Attack, f32)> = Vec::new();
+
+    for (e, f, v, mut t, mut flt) in query.iter_mut() {
+        let mut hit = false;
+
+        let projectile = projectiles
+            .iter()
+            .find(|(handle, _)| *handle == f.player_handle)
+            .map(|(_, projectile)| *projectile);
+
+        for (target, p, hero, _, p_t) in player_query.iter() {
+            if p.handle == f.player_handle || p.invulnerable > 0 {
+                continue;
+            }
+
+            let (projectile, def) =
+                match (projectile, definitions.get(*hero, &hero_assets)) {
+                    (Some(projectile), Some(def)) => (projectile, def),
+                    _ => continue,
+                };
+
+            if fireball_hits(&t, projectile.size, p_t, &def.hurtbox) {
+                let attack = &projectile.attack;
+                hits.push((f.player_handle, target, attack, v.linvel.x));
+                hit = true;
             }
         }
 
         t.translation.x += v.linvel.x;
-        ft.0.tick(Duration::from_millis(time.delta().as_millis() as u64));
-        flt.0
-            .tick(Duration::from_millis(time.delta().as_millis() as u64));
-        if flt.0.finished() {
+        flt.0.tick(FRAME_DURATION);
+        if hit || flt.0.finished() {
             commands.entity(e).despawn();
         }
     }
+
+    // Sorted like the melee casts: fireballs landing on one player in the
+    // same frame stack their damage in the same order on every peer
+    hits.sort_by_key(|(caster, ..)| *caster);
+    for (_, target, attack, direction) in hits {
+        if let Ok((_, mut p, _, mut p_v, _)) = player_query.get_mut(target) {
+            apply_hit(attack, direction, &mut p, &mut p_v);
+        }
+    }
+}
+
+/// Whether a fireball of `size` at `fireball` overlaps the hurtbox of a
+/// player at `target`.
+fn fireball_hits(
+    fireball: &Transform,
+    size: Vec2,
+    target: &Transform,
+    hurtbox: &Hurtbox,
+) -> bool {
+    let center = player::hurtbox_center(target, hurtbox);
+    collide_aabb::collide(
+        fireball.translation,
+        size,
+        center.extend(target.translation.z),
+        player::hurtbox_size(hurtbox),
+    )
+    .is_some()
 }
 
 pub fn animate_fireball_system(
-    time: Res<Time>,
     texture_atlases: Res<Assets<TextureAtlas>>,
     mut query: Query<(
         &Fireball,
@@ -225,7 +408,7 @@ pub fn animate_fireball_system(
     )>,
 ) {
     for (_, mut timer, mut sprite, texture_atlas_handle) in &mut query {
-        timer.0.tick(time.delta());
+        timer.0.tick(FRAME_DURATION);
         if timer.0.just_finished() {
             let texture_atlas =
                 texture_atlases.get(texture_atlas_handle).unwrap();
@@ -233,3 +416,157 @@ pub fn animate_fireball_system(
         }
     }
 }
+
+/// KOs players outside of the blast zone and respawns them while they have
+/// stocks left. Runs in the rollback schedule.
+pub fn ko_system(
+    config: Res<GameConfig>,
+    frame_count: Res<net::FrameCount>,
+    mut outcome: ResMut<MatchOutcome>,
+    mut query: Query<(
+        &mut Player,
+        &mut Transform,
+        &mut Velocity,
+        &mut player::JumpState,
+    )>,
+) {
+    for (mut p, mut t, mut v, mut jump) in query.iter_mut() {
+        if p.stocks == 0 {
+            // Eliminated, keep it out of the way
+            t.translation = ELIMINATED_POSITION;
+            v.linvel = Vec2::ZERO;
+            continue;
+        }
+
+        if p.invulnerable > 0 {
+            p.invulnerable -= 1;
+        }
+
+        if config.blast_zone.contains(t.translation) {
+            continue;
+        }
+
+        p.stocks -= 1;
+        p.damage = 0.0;
+        p.hitstun = 0;
+        v.linvel = Vec2::ZERO;
+
+        if p.stocks == 0 {
+            t.translation = ELIMINATED_POSITION;
+            continue;
+        }
+
+        let (x, y) = player::SPAWN_POINTS[p.handle];
+        t.translation = Vec3::new(x, y, 1.0);
+        p.invulnerable = RESPAWN_INVULNERABILITY;
+        *jump = player::JumpState::default();
+    }
+
+    if outcome.finished {
+        return;
+    }
+
+    let mut alive = query.iter().filter(|(p, ..)| p.stocks > 0);
+    let first = alive.next();
+
+    if alive.next().is_none() {
+        outcome.finished = true;
+        outcome.finished_at = frame_count.frame + 1;
+        outcome.has_winner = first.is_some();
+        outcome.winner = first.map(|(p, ..)| p.handle).unwrap_or_default();
+    }
+}
+
+/// Shows the results once the frame that decided the match is confirmed.
+/// Until then a rollback may still take the final KO back.
+fn end_match(
+    mut state: ResMut<State<GameStage>>,
+    outcome: Res<MatchOutcome>,
+    frame_count: Res<net::FrameCount>,
+    session: Option<Res<P2PSession<net::GGRSConfig>>>,
+    late_spectators: Res<late_join::LateSpectators>,
+    mut recorder: ResMut<replay::ReplayRecorder>,
+    mut logger: ResMut<Logger>,
+) {
+    if !outcome.finished {
+        return;
+    }
+
+    let confirmed = net::confirmed_frames(session.as_deref(), &frame_count);
+    if outcome.finished_at > confirmed {
+        return;
+    }
+
+    // Spectators that joined late get the last frames before we go, or
+    // until they time out
+    if !late_spectators.caught_up(outcome.finished_at) {
+        return;
+    }
+
+    // A disconnect in the same frame already sends us back to the lobby
+    if state.set(GameStage::MatchEnd).is_err() {
+        return;
+    }
+
+    // Frames simulated past the end may still be predicted
+    recorder.finish(outcome.finished_at);
+
+    if outcome.has_winner {
+        logger.info(format!("Player {} wins!", outcome.winner + 1));
+    } else {
+        logger.info("Match ended in a draw.".to_string());
+    }
+}
+
+fn rematch(mut state: ResMut<State<GameStage>>) {
+    state.set(GameStage::Lobby).unwrap();
+}
+
+/// Removes players and fireballs when leaving the lobby or a match
+fn despawn_match_entities(
+    mut commands: Commands,
+    query: Query<Entity, Or<(With<Player>, With<Fireball>)>>,
+) {
+    for e in query.iter() {
+        commands.entity(e).despawn_recursive();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const FIREBALL_SIZE: Vec2 = Vec2::new(25.0, 25.0);
+
+    // Venomancer's, its body fills the lower half of its sprite
+    fn hurtbox() -> Hurtbox {
+        Hurtbox {
+            half_extents: Vec2::new(25.0, 30.0),
+            offset: Vec2::new(0.0, -40.0),
+        }
+    }
+
+    fn hits(fireball: Vec2, hurtbox: &Hurtbox) -> bool {
+        let fireball = Transform::from_translation(fireball.extend(0.0));
+        fireball_hits(&fireball, FIREBALL_SIZE, &Transform::default(), hurtbox)
+    }
+
+    #[test]
+    fn fireballs_hit_the_hurtbox() {
+        assert!(hits(Vec2::new(40.0, -80.0), &hurtbox()));
+        assert!(hits(Vec2::new(-40.0, -130.0), &hurtbox()));
+    }
+
+    #[test]
+    fn fireballs_miss_beside_the_hurtbox() {
+        // All of them within the 100x100 box every player used to have
+        assert!(!hits(Vec2::new(0.0, 30.0), &hurtbox()));
+
+        let narrow = Hurtbox {
+            half_extents: Vec2::new(10.0, 30.0),
+            ..hurtbox()
+        };
+        assert!(!hits(Vec2::new(40.0, -40.0), &narrow));
+        assert!(hits(Vec2::new(40.0, -40.0), &hurtbox()));
+    }
+}
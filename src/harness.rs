@@ -0,0 +1,155 @@
+use std::{
+    slice, thread,
+    time::{Duration, Instant},
+};
+
+use bevy::prelude::{App, State, Transform, World};
+use bevy_rapier2d::prelude::Velocity;
+use ggrs::P2PSession;
+
+pub use crate::{
+    game::{Fireball, MatchOutcome},
+    headless::InputScript,
+    net::BoxInput,
+    player::{
+        Player, INPUT_ATTACK, INPUT_DOWN, INPUT_LEFT, INPUT_RIGHT,
+        INPUT_SHIELD, INPUT_SPECIAL, INPUT_UP,
+    },
+};
+use crate::{
+    checksum::ChecksumHistory,
+    config::GameConfig,
+    game::GameStage,
+    headless_app,
+    loopback::LoopbackNetwork,
+    net::{self, FrameCount, GGRSConfig},
+};
+
+// Hero definitions load in the background, and online peers go through the
+// lobby before the match can start
+const LOAD_TIMEOUT: Duration = Duration::from_secs(10);
+const LOAD_POLL_INTERVAL: Duration = Duration::from_millis(1);
+
+/// A headless match played from an `InputScript`, for integration tests.
+/// Frames are only simulated when asked for, the world can be inspected in
+/// between.
+pub struct MatchHarness {
+    app: App,
+}
+
+impl MatchHarness {
+    /// Starts the match, returns once it is at its first frame.
+    pub fn new(config: GameConfig, script: InputScript) -> Self {
+        let mut app = headless_app(config, u32::MAX);
+        app.insert_resource(script);
+
+        let mut harness = Self { app };
+        Self::start_together(slice::from_mut(&mut harness));
+        harness
+    }
+
+    /// One peer of an online match over `network`, playing its own handle
+    /// from `script`. Started with the other peers by `start_together`, then
+    /// a GGRS session steps the match in real time. All peers have to be
+    /// updated for it to go on, `advance_to` alone stalls.
+    pub fn online(
+        config: GameConfig,
+        script: InputScript,
+        network: &LoopbackNetwork,
+    ) -> Self {
+        let mut app = headless_app(config, u32::MAX);
+        app.insert_resource(script).insert_resource(network.clone());
+
+        Self { app }
+    }
+
+    /// Updates all harnesses until every one of them is at the first frame
+    /// of its match. Online peers only get there by hearing from each other.
+    pub fn start_together(harnesses: &mut [MatchHarness]) {
+        let started = Instant::now();
+        while harnesses.iter().any(|harness| !harness.in_match()) {
+            if started.elapsed() > LOAD_TIMEOUT {
+                panic!("The match didn't start");
+            }
+
+            for harness in harnesses.iter_mut() {
+                harness.update();
+            }
+            thread::sleep(LOAD_POLL_INTERVAL);
+        }
+    }
+
+    fn in_match(&self) -> bool {
+        *self.app.world.resource::<State<GameStage>>().current()
+            == GameStage::Gameplay
+    }
+
+    /// Runs the app once. Online that simulates as many frames as time
+    /// passed, maybe none.
+    pub fn update(&mut self) {
+        self.app.update();
+    }
+
+    /// Frames simulated so far.
+    pub fn frame(&self) -> u32 {
+        self.app.world.resource::<FrameCount>().frame
+    }
+
+    /// Simulates up to `frame`, or until the match ends before it.
+    pub fn advance_to(&mut self, frame: u32) {
+        while self.frame() < frame && !self.finished() {
+            self.app.update();
+        }
+    }
+
+    pub fn advance(&mut self, frames: u32) {
+        self.advance_to(self.frame() + frames);
+    }
+
+    /// Frames simulated with confirmed inputs only, all of them unless
+    /// online.
+    pub fn confirmed_frames(&self) -> u32 {
+        let world = &self.app.world;
+        let session = world.get_resource::<P2PSession<GGRSConfig>>();
+        net::confirmed_frames(session, world.resource::<FrameCount>())
+    }
+
+    /// Checksum of the state at the end of `frame`, if still remembered.
+    pub fn checksum(&self, frame: u32) -> Option<u64> {
+        self.app.world.resource::<ChecksumHistory>().get(frame)
+    }
+
+    pub fn finished(&self) -> bool {
+        self.app.world.resource::<MatchOutcome>().finished
+    }
+
+    pub fn outcome(&self) -> &MatchOutcome {
+        self.app.world.resource::<MatchOutcome>()
+    }
+
+    /// The player of `handle`, panics if there is none.
+    pub fn player(
+        &mut self,
+        handle: usize,
+    ) -> (&Player, &Transform, &Velocity) {
+        let world = &mut self.app.world;
+        let mut query = world.query::<(&Player, &Transform, &Velocity)>();
+        let player = query
+            .iter(world)
+            .find(|(player, ..)| player.handle == handle);
+
+        player.unwrap_or_else(|| panic!("No player with handle {}", handle))
+    }
+
+    pub fn fireballs(&mut self) -> Vec<(&Fireball, &Transform, &Velocity)> {
+        let world = &mut self.app.world;
+        let mut query = world.query::<(&Fireball, &Transform, &Velocity)>();
+        let fireballs = query.iter(world).collect();
+        fireballs
+    }
+
+    /// Everything not covered by the queries above.
+    pub fn world(&mut self) -> &mut World {
+        &mut self.app.world
+    }
+}
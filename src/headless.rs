@@ -0,0 +1,171 @@
+use bevy::{app::AppExit, prelude::*};
+use bevy_ggrs::SessionType;
+
+use crate::{
+    config::GameConfig,
+    debug_ui::Logger,
+    game::{GameStage, MatchMode},
+    hero::{HeroChoice, HeroDefinition, HeroDefinitions},
+    lobby::{LobbyState, MatchRoster},
+    net::{self, BoxInput, FrameCount},
+    physics::ManualStepping,
+};
+
+#[cfg(feature = "testing")]
+use crate::loopback::LoopbackNetwork;
+
+/// Settings of an app without window or renderer. A local match is stepped
+/// by `step_match` instead of a GGRS session, for a set number of frames.
+/// Sync tests run a session all the same, it resimulates the frames.
+pub struct Headless {
+    /// Frames to simulate before the app exits
+    pub frames: u32,
+}
+
+/// Inputs of the players of a headless match, by player handle. A player
+/// holds an input from the frame it is set for until the next one set, and
+/// stays idle before the first.
+#[derive(Default, Clone, Debug)]
+pub struct InputScript {
+    timelines: Vec<Vec<(u32, BoxInput)>>,
+}
+
+impl InputScript {
+    /// Holds `input` from `frame` on.
+    pub fn hold(mut self, handle: usize, frame: u32, input: BoxInput) -> Self {
+        if self.timelines.len() <= handle {
+            self.timelines.resize(handle + 1, Vec::new());
+        }
+
+        let timeline = &mut self.timelines[handle];
+        timeline.retain(|(set_at, _)| *set_at != frame);
+        timeline.push((frame, input));
+        timeline.sort_by_key(|(set_at, _)| *set_at);
+        self
+    }
+
+    /// Presses `buttons` in `frame` only, idle from the next one.
+    pub fn tap(self, handle: usize, frame: u32, buttons: u16) -> Self {
+        self.hold(handle, frame, BoxInput::new(buttons, Vec2::ZERO))
+            .hold(handle, frame + 1, idle_input())
+    }
+
+    pub fn input(&self, handle: usize, frame: u32) -> BoxInput {
+        self.timelines
+            .get(handle)
+            .and_then(|timeline| {
+                timeline.iter().rev().find(|(set_at, _)| *set_at <= frame)
+            })
+            .map(|(_, input)| *input)
+            .unwrap_or_else(idle_input)
+    }
+}
+
+fn idle_input() -> BoxInput {
+    BoxInput::new(0, Vec2::ZERO)
+}
+
+/// Skips the menus: starts a local match once the heroes are loaded,
+/// simulates it with the `InputScript` and exits after `frames` frames or
+/// when the match ends. Over a loopback network it goes through the lobby
+/// instead, ready right away, and GGRS takes the inputs from the script.
+pub struct HeadlessPlugin {
+    pub frames: u32,
+}
+
+impl Plugin for HeadlessPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(Headless {
+            frames: self.frames,
+        })
+        .insert_resource(InputScript::default())
+        .insert_resource(ManualStepping)
+        .add_system_set(
+            SystemSet::on_update(GameStage::MainMenu).with_system(start_match),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::Lobby).with_system(ready_up),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::Gameplay)
+                .with_system(step_match.exclusive_system()),
+        )
+        .add_system_set(
+            SystemSet::on_enter(GameStage::MatchEnd).with_system(exit),
+        );
+    }
+}
+
+fn start_match(
+    mut commands: Commands,
+    mut state: ResMut<State<GameStage>>,
+    mut mode: ResMut<MatchMode>,
+    mut roster: ResMut<MatchRoster>,
+    config: Res<GameConfig>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    #[cfg(feature = "testing")] network: Option<Res<LoopbackNetwork>>,
+    mut logger: ResMut<Logger>,
+) {
+    // Players can't be spawned without their definitions
+    if !definitions.all_loaded(&hero_assets) {
+        return;
+    }
+
+    // The lobby picks up the network and starts the session
+    #[cfg(feature = "testing")]
+    if network.is_some() {
+        *mode = MatchMode::Online;
+        state.set(GameStage::Lobby).unwrap();
+        return;
+    }
+
+    *mode = MatchMode::LocalVersus;
+    *roster =
+        MatchRoster::local(config.num_players, &config, HeroChoice::default());
+
+    // GGRS steps the match from the script, and panics on the first frame
+    // that resimulates differently
+    if let Some(check_distance) = config.sync_test {
+        net::start_local_session(
+            &mut commands,
+            config.num_players,
+            check_distance,
+        );
+        logger.info(
+            "Sync test session started, check distance: ".to_string()
+                + &check_distance.to_string(),
+        );
+    }
+
+    state.set(GameStage::Gameplay).unwrap();
+}
+
+fn ready_up(mut lobby: ResMut<LobbyState>) {
+    lobby.ready = true;
+}
+
+/// One rollback frame per update, as fast as the app loops. Online and in
+/// sync tests, the GGRS session steps the match.
+fn step_match(world: &mut World) {
+    let frame = world.resource::<FrameCount>().frame;
+    if frame >= world.resource::<Headless>().frames {
+        world.resource_mut::<Events<AppExit>>().send(AppExit);
+        return;
+    }
+
+    if world.contains_resource::<SessionType>() {
+        return;
+    }
+
+    let num_players = world.resource::<MatchRoster>().heroes.len();
+    let script = world.resource::<InputScript>();
+    let inputs: Vec<BoxInput> = (0..num_players)
+        .map(|handle| script.input(handle, frame))
+        .collect();
+    net::advance_frame(world, &inputs);
+}
+
+fn exit(mut exit: EventWriter<AppExit>) {
+    exit.send(AppExit);
+}
@@ -0,0 +1,404 @@
+use std::{
+    collections::{HashMap, HashSet},
+    mem,
+};
+
+use bevy::{
+    asset::{AssetLoader, LoadContext, LoadedAsset},
+    prelude::*,
+    reflect::TypeUuid,
+    utils::BoxedFuture,
+};
+use bevy_rapier2d::prelude::{Collider, GravityScale};
+use ggrs::P2PSession;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    checksum::StateHasher, combat::Attack, debug_ui::Logger, game::FPS,
+    net::GGRSConfig,
+};
+
+/// The hero a player plays. Fixed for the whole match, so it is not rolled
+/// back. Its stats live in `assets/heroes/<name>.hero.ron`.
+#[derive(
+    Component,
+    Clone,
+    Copy,
+    PartialEq,
+    Eq,
+    Hash,
+    Debug,
+    Default,
+    Serialize,
+    Deserialize,
+)]
+pub enum Hero {
+    #[default]
+    Venomancer,
+    Axe,
+}
+
+/// A hero together with the skin it is played in, as picked by a player.
+#[derive(
+    Clone, Copy, PartialEq, Eq, Debug, Default, Serialize, Deserialize,
+)]
+pub struct HeroChoice {
+    pub hero: Hero,
+    /// Index into the hero's `skins`
+    pub skin: u8,
+}
+
+/// Hero picked in the hero select, played in the lobby and sent to peers.
+#[derive(Default)]
+pub struct SelectedHero(pub HeroChoice);
+
+impl Hero {
+    pub const ALL: [Hero; 2] = [Hero::Venomancer, Hero::Axe];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Hero::Venomancer => "Venomancer",
+            Hero::Axe => "Axe",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Hero> {
+        Hero::ALL
+            .into_iter()
+            .find(|hero| hero.name().eq_ignore_ascii_case(name))
+    }
+
+    /// Position in `Hero::ALL`, how the hero is sent over the network.
+    pub fn index(&self) -> u8 {
+        Hero::ALL.iter().position(|hero| hero == self).unwrap() as u8
+    }
+
+    pub fn from_index(index: u8) -> Option<Hero> {
+        Hero::ALL.get(index as usize).copied()
+    }
+
+    fn definition_path(&self) -> String {
+        format!("heroes/{}.hero.ron", self.name().to_lowercase())
+    }
+}
+
+/// Everything that makes up a hero: looks, body and ability kit.
+#[derive(Deserialize, TypeUuid, Clone, Debug)]
+#[uuid = "5b1b4e8e-2a0c-4d8f-9a51-6f3e0c1d7a42"]
+pub struct HeroDefinition {
+    pub idle: SpriteSheet,
+    /// Selectable looks, the first one is the default
+    pub skins: Vec<Skin>,
+    pub hurtbox: Hurtbox,
+    pub movement: Movement,
+    /// Bound to attack (space)
+    pub primary: Ability,
+    /// Bound to special (E)
+    pub special: Option<Ability>,
+}
+
+impl HeroDefinition {
+    /// The projectile of the hero's kit, if it has one.
+    pub fn projectile(&self) -> Option<&Projectile> {
+        self.abilities().find_map(|ability| match ability {
+            Ability::Fireball(projectile) => Some(projectile),
+            _ => None,
+        })
+    }
+
+    /// The skin at `index`, falling back to the default skin for unknown
+    /// indices, e.g. sent by a peer with other hero definitions.
+    pub fn skin(&self, index: u8) -> Option<&Skin> {
+        self.skins.get(index as usize).or_else(|| self.skins.first())
+    }
+
+    fn abilities(&self) -> impl Iterator<Item = &Ability> {
+        std::iter::once(&self.primary).chain(self.special.iter())
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Skin {
+    pub name: String,
+    /// Multiplied with the sprite, on top of the player color
+    pub tint: Color,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct SpriteSheet {
+    pub path: String,
+    pub tile_size: Vec2,
+    pub columns: usize,
+    pub rows: usize,
+    pub frame_seconds: f32,
+}
+
+/// Collider of the hero, relative to the unscaled sprite.
+#[derive(Deserialize, Clone, Debug)]
+pub struct Hurtbox {
+    pub half_extents: Vec2,
+    pub offset: Vec2,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Movement {
+    /// Horizontal movement speed
+    pub speed: f32,
+    pub gravity_scale: f32,
+    /// Vertical speed a jump starts with
+    pub jump_speed: f32,
+    /// Jumps in the air before the hero has to touch the ground again
+    pub air_jumps: u32,
+    /// Falling speed while holding down in the air
+    pub fast_fall_speed: f32,
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub enum Ability {
+    /// Projectile flying straight ahead
+    Fireball(Projectile),
+    /// Spin hitting everyone around the hero
+    CounterHelix {
+        radius: f32,
+        attack: Attack,
+        cooldown_frames: u32,
+    },
+    /// Pulls everyone around the hero in and taunts them
+    BerserkersCall {
+        radius: f32,
+        pull_speed: f32,
+        taunt_frames: u32,
+        cooldown_frames: u32,
+    },
+}
+
+impl Ability {
+    pub fn cooldown_frames(&self) -> u32 {
+        match self {
+            Ability::Fireball(projectile) => projectile.cooldown_frames(),
+            Ability::CounterHelix {
+                cooldown_frames, ..
+            } => *cooldown_frames,
+            Ability::BerserkersCall {
+                cooldown_frames, ..
+            } => *cooldown_frames,
+        }
+    }
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct Projectile {
+    pub sprite: SpriteSheet,
+    /// Collision size
+    pub size: Vec2,
+    /// Distance travelled per frame
+    pub speed: f32,
+    /// Distance in front of the hero it spawns at
+    pub spawn_offset: f32,
+    pub cooldown_seconds: f32,
+    pub lifetime_seconds: f32,
+    pub attack: Attack,
+}
+
+impl Projectile {
+    pub fn cooldown_frames(&self) -> u32 {
+        (self.cooldown_seconds * FPS).round() as u32
+    }
+}
+
+#[derive(Default)]
+pub struct HeroDefinitionLoader;
+
+impl AssetLoader for HeroDefinitionLoader {
+    fn load<'a>(
+        &'a self,
+        bytes: &'a [u8],
+        load_context: &'a mut LoadContext,
+    ) -> BoxedFuture<'a, Result<(), anyhow::Error>> {
+        Box::pin(async move {
+            let definition: HeroDefinition = ron::de::from_bytes(bytes)?;
+            load_context.set_default_asset(LoadedAsset::new(definition));
+            Ok(())
+        })
+    }
+
+    fn extensions(&self) -> &[&str] {
+        &["hero.ron"]
+    }
+}
+
+/// Handles to the definitions of all heroes, loaded on startup.
+#[derive(Default)]
+pub struct HeroDefinitions {
+    handles: HashMap<Hero, Handle<HeroDefinition>>,
+    /// Copies of the definitions in use, put back when one is edited during
+    /// an online match
+    in_use: HashMap<Hero, HeroDefinition>,
+    /// Edits held back until the online match is over
+    held_back: HashMap<Hero, HeroDefinition>,
+    /// Heroes whose definition was just put back, their next modification
+    /// is our own
+    restored: HashSet<Hero>,
+}
+
+impl HeroDefinitions {
+    pub fn get<'a>(
+        &self,
+        hero: Hero,
+        assets: &'a Assets<HeroDefinition>,
+    ) -> Option<&'a HeroDefinition> {
+        self.handles.get(&hero).and_then(|handle| assets.get(handle))
+    }
+
+    /// Hash over the definitions of all heroes as loaded. Matches only play
+    /// out the same with the same definitions, replays keep it to tell.
+    pub fn hash(&self, assets: &Assets<HeroDefinition>) -> u64 {
+        let mut hasher = StateHasher::new();
+        for hero in Hero::ALL {
+            match self.get(hero, assets) {
+                // Floats are printed exactly, the same on every platform
+                Some(definition) => {
+                    hasher.write_bytes(format!("{:?}", definition).as_bytes())
+                }
+                None => hasher.write_u32(0),
+            }
+        }
+        hasher.finish()
+    }
+
+    pub fn all_loaded(&self, assets: &Assets<HeroDefinition>) -> bool {
+        Hero::ALL.iter().all(|hero| self.get(*hero, assets).is_some())
+    }
+}
+
+pub struct HeroPlugin;
+
+impl Plugin for HeroPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_asset::<HeroDefinition>()
+            .init_asset_loader::<HeroDefinitionLoader>()
+            .insert_resource(SelectedHero::default())
+            .add_startup_system(load_hero_definitions)
+            .add_system(apply_hero_reloads);
+    }
+}
+
+fn load_hero_definitions(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+) {
+    let handles = Hero::ALL
+        .into_iter()
+        .map(|hero| {
+            let path = hero.definition_path();
+            (hero, asset_server.load(path.as_str()))
+        })
+        .collect();
+
+    commands.insert_resource(HeroDefinitions {
+        handles,
+        ..Default::default()
+    });
+}
+
+/// Pushes edited hero definitions onto the spawned players. Everything else
+/// reads the definitions every frame and picks changes up on its own.
+///
+/// Peers of an online match have to simulate it with the same definitions,
+/// edits made during one are held back until it is over.
+fn apply_hero_reloads(
+    mut asset_events: EventReader<AssetEvent<HeroDefinition>>,
+    mut definitions: ResMut<HeroDefinitions>,
+    mut assets: ResMut<Assets<HeroDefinition>>,
+    session: Option<Res<P2PSession<GGRSConfig>>>,
+    mut players: Query<(&Hero, &mut GravityScale, &Children)>,
+    mut colliders: Query<(&mut Collider, &mut Transform)>,
+    mut logger: ResMut<Logger>,
+) {
+    let online = session.is_some();
+
+    // Comes back as a modification of the asset, applied below next frame
+    if !online {
+        for (hero, definition) in mem::take(&mut definitions.held_back) {
+            let handle = definitions.handles.get(&hero);
+            if let Some(asset) =
+                handle.and_then(|handle| assets.get_mut(handle))
+            {
+                *asset = definition;
+            }
+        }
+    }
+
+    for event in asset_events.iter() {
+        let (handle, created) = match event {
+            AssetEvent::Created { handle } => (handle, true),
+            AssetEvent::Modified { handle } => (handle, false),
+            _ => continue,
+        };
+
+        let hero = Hero::ALL
+            .into_iter()
+            .find(|hero| definitions.handles.get(hero) == Some(handle));
+        let hero = match hero {
+            Some(hero) => hero,
+            None => continue,
+        };
+
+        let definition = match assets.get(handle) {
+            Some(definition) => definition.clone(),
+            None => continue,
+        };
+
+        if created {
+            definitions.in_use.insert(hero, definition);
+            continue;
+        }
+
+        if definitions.restored.remove(&hero) {
+            continue;
+        }
+
+        if online {
+            let in_use = match definitions.in_use.get(&hero) {
+                Some(in_use) => in_use.clone(),
+                None => continue,
+            };
+
+            if let Some(asset) = assets.get_mut(handle) {
+                *asset = in_use;
+                definitions.restored.insert(hero);
+                definitions.held_back.insert(hero, definition);
+                logger.warn(format!(
+                    "{} changed during an online match, reloaded after it",
+                    hero.name()
+                ));
+            }
+            continue;
+        }
+
+        definitions.in_use.insert(hero, definition.clone());
+        logger.info("Reloaded hero definition: ".to_string() + hero.name());
+
+        for (player_hero, mut gravity_scale, children) in players.iter_mut() {
+            if *player_hero != hero {
+                continue;
+            }
+
+            gravity_scale.0 = definition.movement.gravity_scale;
+
+            for child in children.iter() {
+                if let Ok((mut collider, mut transform)) =
+                    colliders.get_mut(*child)
+                {
+                    let hurtbox = &definition.hurtbox;
+                    *collider = Collider::cuboid(
+                        hurtbox.half_extents.x,
+                        hurtbox.half_extents.y,
+                    );
+                    transform.translation =
+                        hurtbox.offset.extend(transform.translation.z);
+                }
+            }
+        }
+    }
+}
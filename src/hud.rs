@@ -0,0 +1,63 @@
+use bevy::prelude::*;
+use bevy_egui::{egui::Window, EguiContext};
+
+use crate::{net::ConnectionStatus, player::Player};
+
+/// In-match overlay showing every player's damage. Needs the `EguiPlugin`,
+/// which the `DebugUiPlugin` adds.
+pub struct HudPlugin;
+
+impl Plugin for HudPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_system(player_hud).add_system(connection_hud);
+    }
+}
+
+fn player_hud(
+    mut egui_context: ResMut<EguiContext>,
+    query: Query<&Player>,
+) {
+    let mut players: Vec<&Player> = query.iter().collect();
+    players.sort_by_key(|p| p.handle);
+
+    Window::new("Players").show(egui_context.ctx_mut(), |ui| {
+        for player in players {
+            ui.label(format!(
+                "P{}: {:.0}%  Stocks: {}",
+                player.handle + 1,
+                player.damage,
+                player.stocks
+            ));
+        }
+    });
+}
+
+fn connection_hud(
+    mut egui_context: ResMut<EguiContext>,
+    status: Res<ConnectionStatus>,
+) {
+    if status.synchronizing.is_empty()
+        && status.interrupted.is_empty()
+        && status.wait_indicator_frames == 0
+    {
+        return;
+    }
+
+    Window::new("Connection").show(egui_context.ctx_mut(), |ui| {
+        for (peer, (count, total)) in status.synchronizing.iter() {
+            ui.label(format!("Synchronizing {}: {}/{}", peer, count, total));
+        }
+        for (peer, timeout) in status.interrupted.iter() {
+            ui.label(format!(
+                "Connection to {} interrupted, disconnect in {}ms",
+                peer, timeout
+            ));
+        }
+        if status.wait_indicator_frames > 0 {
+            ui.label(format!(
+                "Waiting for peers: {} frames ahead",
+                status.wait_frames
+            ));
+        }
+    });
+}
@@ -0,0 +1,425 @@
+use std::{collections::HashMap, marker::PhantomData};
+
+use bevy::{
+    ecs::system::SystemParam,
+    prelude::{
+        warn, App, Axis, Gamepad, GamepadAxis, GamepadAxisType, GamepadButton,
+        GamepadButtonType, Gamepads, Input, KeyCode, Plugin, Res, Vec2,
+    },
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    net::BoxInput,
+    player::{
+        INPUT_ATTACK, INPUT_DOWN, INPUT_LEFT, INPUT_RIGHT, INPUT_SHIELD,
+        INPUT_SPECIAL, INPUT_UP,
+    },
+};
+
+// Where the bindings are stored, next to `dota_smash.cfg`
+#[cfg(not(target_arch = "wasm32"))]
+const SETTINGS_PATH: &str = "settings.ron";
+
+// How far a stick has to be pushed past the deadzone to count as a direction
+const STICK_THRESHOLD: f32 = 0.5;
+const DEFAULT_DEADZONE: f32 = 0.2;
+
+/// What a player wants to do, independent of the button they pressed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum Action {
+    MoveLeft,
+    MoveRight,
+    MoveDown,
+    Jump,
+    Attack,
+    Special,
+    Shield,
+}
+
+impl Action {
+    pub const ALL: [Action; 7] = [
+        Action::MoveLeft,
+        Action::MoveRight,
+        Action::MoveDown,
+        Action::Jump,
+        Action::Attack,
+        Action::Special,
+        Action::Shield,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Action::MoveLeft => "Move left",
+            Action::MoveRight => "Move right",
+            Action::MoveDown => "Move down",
+            Action::Jump => "Jump",
+            Action::Attack => "Attack",
+            Action::Special => "Special",
+            Action::Shield => "Shield",
+        }
+    }
+
+    fn bit(&self) -> u16 {
+        1 << *self as u16
+    }
+}
+
+/// Actions held down by a player in the current frame.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct ActionState {
+    pressed: u16,
+    /// Left stick after the deadzone, zero on keyboards
+    pub stick: Vec2,
+}
+
+impl ActionState {
+    pub fn pressed(&self, action: Action) -> bool {
+        self.pressed & action.bit() != 0
+    }
+
+    fn press(&mut self, action: Action) {
+        self.pressed |= action.bit();
+    }
+
+    /// Actions of two devices, for a player holding both.
+    pub fn merge(self, other: ActionState) -> ActionState {
+        let stick = if self.stick.length_squared() > 0.0 {
+            self.stick
+        } else {
+            other.stick
+        };
+
+        ActionState {
+            pressed: self.pressed | other.pressed,
+            stick,
+        }
+    }
+
+    /// The actions as sent to the peers.
+    pub fn to_input(&self) -> BoxInput {
+        let bits = [
+            (Action::Jump, INPUT_UP),
+            (Action::MoveDown, INPUT_DOWN),
+            (Action::MoveLeft, INPUT_LEFT),
+            (Action::MoveRight, INPUT_RIGHT),
+            (Action::Attack, INPUT_ATTACK),
+            (Action::Special, INPUT_SPECIAL),
+            (Action::Shield, INPUT_SHIELD),
+        ];
+
+        let buttons = bits
+            .into_iter()
+            .filter(|(action, _)| self.pressed(*action))
+            .fold(0, |input, (_, bit)| input | bit);
+
+        BoxInput::new(buttons, self.stick)
+    }
+}
+
+/// Part of the keyboard a player plays on, so several players can share one.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug, Serialize, Deserialize)]
+pub enum KeyboardRegion {
+    Wasd,
+    Arrows,
+    Ijkl,
+    Numpad,
+}
+
+impl KeyboardRegion {
+    pub const ALL: [KeyboardRegion; 4] = [
+        KeyboardRegion::Wasd,
+        KeyboardRegion::Arrows,
+        KeyboardRegion::Ijkl,
+        KeyboardRegion::Numpad,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            KeyboardRegion::Wasd => "Keyboard (WASD)",
+            KeyboardRegion::Arrows => "Keyboard (Arrows)",
+            KeyboardRegion::Ijkl => "Keyboard (IJKL)",
+            KeyboardRegion::Numpad => "Keyboard (Numpad)",
+        }
+    }
+
+    /// Bindings of the region before any rebinding.
+    pub fn default_bindings(&self) -> KeyBindings {
+        let keys = match self {
+            KeyboardRegion::Wasd => [
+                KeyCode::A,
+                KeyCode::D,
+                KeyCode::S,
+                KeyCode::W,
+                KeyCode::Space,
+                KeyCode::E,
+                KeyCode::Q,
+            ],
+            KeyboardRegion::Arrows => [
+                KeyCode::Left,
+                KeyCode::Right,
+                KeyCode::Down,
+                KeyCode::Up,
+                KeyCode::RControl,
+                KeyCode::RShift,
+                KeyCode::Return,
+            ],
+            KeyboardRegion::Ijkl => [
+                KeyCode::J,
+                KeyCode::L,
+                KeyCode::K,
+                KeyCode::I,
+                KeyCode::U,
+                KeyCode::O,
+                KeyCode::P,
+            ],
+            KeyboardRegion::Numpad => [
+                KeyCode::Numpad4,
+                KeyCode::Numpad6,
+                KeyCode::Numpad5,
+                KeyCode::Numpad8,
+                KeyCode::Numpad7,
+                KeyCode::Numpad9,
+                KeyCode::Numpad0,
+            ],
+        };
+
+        // In `Action::ALL` order
+        KeyBindings {
+            keys: Action::ALL.into_iter().zip(keys).collect(),
+        }
+    }
+}
+
+/// Key of every action, actions without a key are unbound.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct KeyBindings {
+    pub keys: HashMap<Action, KeyCode>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadBindings {
+    pub buttons: HashMap<Action, GamepadButtonType>,
+    /// Stick deflection below this is ignored, worn sticks don't rest at zero
+    pub deadzone: f32,
+}
+
+impl Default for GamepadBindings {
+    fn default() -> Self {
+        let buttons = [
+            (Action::MoveLeft, GamepadButtonType::DPadLeft),
+            (Action::MoveRight, GamepadButtonType::DPadRight),
+            (Action::MoveDown, GamepadButtonType::DPadDown),
+            (Action::Jump, GamepadButtonType::South),
+            (Action::Attack, GamepadButtonType::West),
+            (Action::Special, GamepadButtonType::East),
+            (Action::Shield, GamepadButtonType::RightTrigger),
+        ];
+
+        Self {
+            buttons: buttons.into_iter().collect(),
+            deadzone: DEFAULT_DEADZONE,
+        }
+    }
+}
+
+/// Rebindable controls, persisted to `SETTINGS_PATH` on native.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct InputSettings {
+    /// Regions missing here play with their default bindings
+    pub keyboard: HashMap<KeyboardRegion, KeyBindings>,
+    /// Shared by all gamepads
+    pub gamepad: GamepadBindings,
+}
+
+impl InputSettings {
+    pub fn keyboard(&self, region: KeyboardRegion) -> KeyBindings {
+        self.keyboard
+            .get(&region)
+            .cloned()
+            .unwrap_or_else(|| region.default_bindings())
+    }
+
+    pub fn keyboard_mut(
+        &mut self,
+        region: KeyboardRegion,
+    ) -> &mut KeyBindings {
+        self.keyboard
+            .entry(region)
+            .or_insert_with(|| region.default_bindings())
+    }
+
+    /// The saved settings, the defaults if there are none or they are broken.
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn load() -> Self {
+        let contents = match std::fs::read_to_string(SETTINGS_PATH) {
+            Ok(contents) => contents,
+            Err(_) => return Self::default(),
+        };
+
+        match ron::from_str(&contents) {
+            Ok(settings) => settings,
+            Err(e) => {
+                warn!("Invalid {}, using defaults: {}", SETTINGS_PATH, e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Browsers have no settings file, bindings last for the session.
+    #[cfg(target_arch = "wasm32")]
+    pub fn load() -> Self {
+        Self::default()
+    }
+
+    #[cfg(not(target_arch = "wasm32"))]
+    pub fn save(&self) {
+        let pretty = ron::ser::PrettyConfig::new();
+        let result = ron::ser::to_string_pretty(self, pretty)
+            .map_err(|e| e.to_string())
+            .and_then(|contents| {
+                std::fs::write(SETTINGS_PATH, contents)
+                    .map_err(|e| e.to_string())
+            });
+
+        if let Err(e) = result {
+            warn!("Failed to save {}: {}", SETTINGS_PATH, e);
+        }
+    }
+
+    #[cfg(target_arch = "wasm32")]
+    pub fn save(&self) {}
+}
+
+/// The device a player controls their hero with.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Controls {
+    Keyboard(KeyboardRegion),
+    Gamepad(Gamepad),
+}
+
+impl Default for Controls {
+    fn default() -> Self {
+        Controls::Keyboard(KeyboardRegion::Wasd)
+    }
+}
+
+impl Controls {
+    /// Every player on their own keyboard region, until they pick a gamepad.
+    pub fn for_handle(handle: usize) -> Controls {
+        let region = KeyboardRegion::ALL[handle % KeyboardRegion::ALL.len()];
+        Controls::Keyboard(region)
+    }
+
+    pub fn name(&self) -> String {
+        match self {
+            Controls::Keyboard(region) => region.name().to_string(),
+            Controls::Gamepad(gamepad) => format!("Gamepad {}", gamepad.0 + 1),
+        }
+    }
+}
+
+/// Everything needed to turn pressed buttons into actions.
+#[derive(SystemParam)]
+pub struct InputDevices<'w, 's> {
+    keyboard: Res<'w, Input<KeyCode>>,
+    gamepad_buttons: Res<'w, Input<GamepadButton>>,
+    gamepad_axes: Res<'w, Axis<GamepadAxis>>,
+    gamepads: Res<'w, Gamepads>,
+    settings: Res<'w, InputSettings>,
+    #[system_param(ignore)]
+    _marker: PhantomData<&'s ()>,
+}
+
+impl<'w, 's> InputDevices<'w, 's> {
+    pub fn actions(&self, controls: Controls) -> ActionState {
+        match controls {
+            Controls::Keyboard(region) => self.keyboard_actions(region),
+            Controls::Gamepad(gamepad) => self.gamepad_actions(gamepad),
+        }
+    }
+
+    /// A player alone on this machine plays with the first keyboard region
+    /// or the first gamepad, whichever they grab.
+    pub fn primary_actions(&self) -> ActionState {
+        let keyboard = self.keyboard_actions(KeyboardRegion::Wasd);
+
+        match self.gamepads.iter().next() {
+            Some(gamepad) => keyboard.merge(self.gamepad_actions(*gamepad)),
+            None => keyboard,
+        }
+    }
+
+    fn keyboard_actions(&self, region: KeyboardRegion) -> ActionState {
+        let mut actions = ActionState::default();
+
+        for (action, key) in self.settings.keyboard(region).keys {
+            if self.keyboard.pressed(key) {
+                actions.press(action);
+            }
+        }
+
+        actions
+    }
+
+    fn gamepad_actions(&self, gamepad: Gamepad) -> ActionState {
+        let bindings = &self.settings.gamepad;
+        let mut actions = ActionState::default();
+
+        for (action, button) in bindings.buttons.iter() {
+            if self.gamepad_buttons.pressed(GamepadButton(gamepad, *button)) {
+                actions.press(*action);
+            }
+        }
+
+        let axis = |axis| {
+            self.gamepad_axes
+                .get(GamepadAxis(gamepad, axis))
+                .unwrap_or(0.0)
+        };
+        let stick = Vec2::new(
+            axis(GamepadAxisType::LeftStickX),
+            axis(GamepadAxisType::LeftStickY),
+        );
+        actions.stick = apply_deadzone(stick, bindings.deadzone);
+
+        // Pushing the stick up jumps, like tapping jump
+        if actions.stick.y > STICK_THRESHOLD {
+            actions.press(Action::Jump);
+        }
+        if actions.stick.y < -STICK_THRESHOLD {
+            actions.press(Action::MoveDown);
+        }
+        if actions.stick.x < -STICK_THRESHOLD {
+            actions.press(Action::MoveLeft);
+        }
+        if actions.stick.x > STICK_THRESHOLD {
+            actions.press(Action::MoveRight);
+        }
+
+        actions
+    }
+}
+
+/// Zero inside the deadzone, rescaled so the stick still reaches full
+/// deflection outside of it.
+fn apply_deadzone(stick: Vec2, deadzone: f32) -> Vec2 {
+    let length = stick.length();
+
+    if length <= deadzone || deadzone >= 1.0 {
+        return Vec2::ZERO;
+    }
+
+    let scaled = ((length - deadzone) / (1.0 - deadzone)).min(1.0);
+    stick / length * scaled
+}
+
+pub struct ControlsPlugin;
+
+impl Plugin for ControlsPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(InputSettings::load());
+    }
+}
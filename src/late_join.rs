@@ -0,0 +1,481 @@
+use std::collections::HashMap;
+
+use bevy::prelude::{
+    warn, Assets, Commands, Local, Mut, Res, ResMut, State, Time, World,
+};
+use ggrs::P2PSession;
+
+use crate::{
+    config::GameConfig,
+    debug_ui::Logger,
+    desync::{SessionChannel, PACKET_LATE_JOIN_ACK, PACKET_LATE_JOIN_INPUTS},
+    game::{GameStage, MatchMode, FRAME_DURATION},
+    hero::{HeroDefinition, HeroDefinitions},
+    lobby::{LobbyState, MatchRoster},
+    net::{self, BoxInput, FrameCount, GGRSConfig, PeerSocket},
+    physics::ManualStepping,
+    replay::{Replay, ReplayRecorder},
+};
+
+// How often a spectator in the lobby asks the peers for a running match
+const LATE_JOIN_REQUEST_SECONDS: f32 = 0.5;
+// How often the host resends the inputs a spectator didn't ack yet
+const LATE_JOIN_SEND_SECONDS: f32 = 0.05;
+// The host sends this often even without new inputs, so the spectator can
+// tell a stalled match from a lost connection
+const LATE_JOIN_KEEPALIVE_SECONDS: f32 = 1.0;
+// A two seconds replay, well within a datagram
+const MAX_LATE_JOIN_FRAMES: u32 = 120;
+// A spectator this long silent left
+const LATE_SPECTATOR_TIMEOUT_SECONDS: f32 = 2.0;
+// The host this long silent left
+const LIVE_MATCH_TIMEOUT_SECONDS: f32 = 3.0;
+// Frames a caught up spectator stays behind the host, so late packets don't
+// stall it
+const LIVE_BUFFER_FRAMES: u32 = 6;
+// Catching up on a running match simulates this many frames per update at
+// most
+const MAX_CATCH_UP_FRAMES_PER_UPDATE: u32 = 30;
+
+/// Packets between the host of a running match and the spectators that
+/// joined it after it started.
+#[derive(Debug, Clone)]
+enum LateJoinPacket {
+    /// Confirmed inputs from frame `start` on, along with the rules and
+    /// heroes of the match
+    Inputs { start: u32, replay: Replay },
+    /// The spectator has the inputs of the first `frames` frames. Asks to
+    /// join the match with zero.
+    Ack { frames: u32 },
+}
+
+impl LateJoinPacket {
+    fn encode(&self) -> Result<Box<[u8]>, String> {
+        let mut packet = Vec::new();
+        match self {
+            LateJoinPacket::Inputs { start, replay } => {
+                packet.push(PACKET_LATE_JOIN_INPUTS);
+                packet.extend(start.to_le_bytes());
+                packet.extend(
+                    bincode::serialize(replay).map_err(|e| e.to_string())?,
+                );
+            }
+            LateJoinPacket::Ack { frames } => {
+                packet.push(PACKET_LATE_JOIN_ACK);
+                packet.extend(frames.to_le_bytes());
+            }
+        }
+        Ok(packet.into_boxed_slice())
+    }
+
+    fn decode(bytes: &[u8]) -> Option<LateJoinPacket> {
+        let (&tag, body) = bytes.split_first()?;
+        let frames = u32::from_le_bytes(body.get(..4)?.try_into().ok()?);
+        let rest = &body[4..];
+
+        match tag {
+            PACKET_LATE_JOIN_INPUTS => Some(LateJoinPacket::Inputs {
+                start: frames,
+                replay: bincode::deserialize(rest).ok()?,
+            }),
+            PACKET_LATE_JOIN_ACK if rest.is_empty() => {
+                Some(LateJoinPacket::Ack { frames })
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Spectators that joined the running match after it started. The player
+/// with the first handle hosts them, sending the confirmed inputs of the
+/// match from its first frame on. The other players drop their requests.
+#[derive(Default)]
+pub struct LateSpectators {
+    channel: Option<SessionChannel>,
+    hosting: bool,
+    spectators: HashMap<String, LateSpectator>,
+}
+
+#[derive(Default)]
+struct LateSpectator {
+    /// Frames the spectator has the inputs of
+    acked: u32,
+    /// Seconds since the spectator was last heard from
+    since_ack: f32,
+    /// Seconds since inputs were last sent to the spectator
+    since_sent: f32,
+}
+
+impl LateSpectators {
+    /// Starts over for a new P2P session.
+    pub fn start(&mut self, channel: &SessionChannel, hosting: bool) {
+        *self = LateSpectators {
+            channel: Some(channel.clone()),
+            hosting,
+            ..Default::default()
+        };
+    }
+
+    /// Whether every spectator has the inputs of the first `frames` frames.
+    pub fn caught_up(&self, frames: u32) -> bool {
+        self.spectators
+            .values()
+            .all(|spectator| spectator.acked >= frames)
+    }
+}
+
+/// Sends every late spectator the confirmed inputs it lacks, resent until
+/// acked.
+pub fn stream_to_late_spectators(
+    time: Res<Time>,
+    frame_count: Res<FrameCount>,
+    session: Option<Res<P2PSession<GGRSConfig>>>,
+    recorder: Res<ReplayRecorder>,
+    mut late: ResMut<LateSpectators>,
+    mut logger: ResMut<Logger>,
+) {
+    let channel = match &late.channel {
+        Some(channel) => channel.clone(),
+        None => return,
+    };
+
+    let packets =
+        channel.receive(PACKET_LATE_JOIN_INPUTS..=PACKET_LATE_JOIN_ACK);
+    if !late.hosting {
+        return;
+    }
+
+    for (peer, packet) in packets {
+        let frames = match LateJoinPacket::decode(&packet) {
+            Some(LateJoinPacket::Ack { frames }) => frames,
+            _ => {
+                warn!("Invalid late join packet from {}", peer);
+                continue;
+            }
+        };
+
+        let spectator =
+            late.spectators.entry(peer.clone()).or_insert_with(|| {
+                logger.info(format!("{} joined the match to spectate", peer));
+                LateSpectator {
+                    // Sent right away
+                    since_sent: LATE_JOIN_SEND_SECONDS,
+                    ..Default::default()
+                }
+            });
+        // Acks may arrive out of order
+        spectator.acked = spectator.acked.max(frames);
+        spectator.since_ack = 0.0;
+    }
+
+    let delta = time.delta_seconds();
+    late.spectators.retain(|peer, spectator| {
+        spectator.since_ack += delta;
+        let active = spectator.since_ack < LATE_SPECTATOR_TIMEOUT_SECONDS;
+        if !active {
+            logger.info(format!("{} stopped spectating", peer));
+        }
+        active
+    });
+
+    // Predicted frames may still change, they are sent once confirmed
+    let confirmed = net::confirmed_frames(session.as_deref(), &frame_count)
+        .min(recorder.num_frames());
+
+    for (peer, spectator) in late.spectators.iter_mut() {
+        spectator.since_sent += delta;
+
+        let behind = spectator.acked < confirmed;
+        if spectator.since_sent < LATE_JOIN_SEND_SECONDS
+            || (!behind && spectator.since_sent < LATE_JOIN_KEEPALIVE_SECONDS)
+        {
+            continue;
+        }
+        spectator.since_sent = 0.0;
+
+        let start = spectator.acked;
+        let end = confirmed.min(start + MAX_LATE_JOIN_FRAMES);
+        let packet = LateJoinPacket::Inputs {
+            start,
+            replay: recorder.excerpt(start, end),
+        };
+
+        match packet.encode() {
+            Ok(packet) => channel.send(peer, packet),
+            Err(e) => warn!("Failed to encode inputs for {}: {}", peer, e),
+        }
+    }
+}
+
+/// Asks the peers of the room for a running match while we wait in the lobby
+/// to spectate. The host of a running match answers with its inputs, see
+/// `join_running_match`. Peers in the lobby ignore the request.
+pub fn request_running_match(
+    socket: Option<ResMut<Option<PeerSocket>>>,
+    mode: Res<MatchMode>,
+    lobby: Res<LobbyState>,
+    time: Res<Time>,
+    mut since_sent: Local<f32>,
+) {
+    if *mode != MatchMode::Spectate {
+        return;
+    }
+
+    let mut socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    // Socket already handed to a session
+    let socket = match socket.as_mut() {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    *since_sent += time.delta_seconds();
+    if *since_sent < LATE_JOIN_REQUEST_SECONDS {
+        return;
+    }
+    *since_sent = 0.0;
+
+    let request = match (LateJoinPacket::Ack { frames: 0 }).encode() {
+        Ok(request) => request,
+        Err(e) => {
+            warn!("Failed to encode late join request: {}", e);
+            return;
+        }
+    };
+
+    for peer in lobby.peers.keys() {
+        socket.send(request.clone(), peer.clone());
+    }
+}
+
+/// A match we joined as a spectator while it was running. Its host sends
+/// the confirmed inputs, frames are simulated as they come in.
+pub struct LiveMatch {
+    socket: PeerSocket,
+    host: String,
+    /// Inputs of every frame received so far
+    frames: Vec<Vec<BoxInput>>,
+    /// Time not yet simulated, in seconds
+    accumulator: f32,
+    /// Seconds since the host was last heard from
+    since_received: f32,
+    /// Rules the match replaced, restored when leaving it
+    previous_config: GameConfig,
+}
+
+impl LiveMatch {
+    /// Takes in the inputs the host sent and acks them.
+    fn receive(&mut self, delta: f32) {
+        // Other peers of the room still send their lobby messages
+        self.socket.accept_new_connections();
+        self.since_received += delta;
+
+        let mut received = false;
+        for (peer, packet) in self.socket.receive() {
+            if peer != self.host {
+                continue;
+            }
+
+            let (start, replay) = match LateJoinPacket::decode(&packet) {
+                Some(LateJoinPacket::Inputs { start, replay }) => {
+                    (start, replay)
+                }
+                _ => continue,
+            };
+            received = true;
+
+            // Resends overlap what we have, packets after a gap are dropped
+            // and come again
+            let have = self.frames.len() as u32;
+            if start <= have {
+                let frames = replay.frames().into_iter();
+                self.frames.extend(frames.skip((have - start) as usize));
+            }
+        }
+
+        if !received {
+            return;
+        }
+        self.since_received = 0.0;
+
+        let ack = LateJoinPacket::Ack {
+            frames: self.frames.len() as u32,
+        };
+        match ack.encode() {
+            Ok(ack) => self.socket.send(ack, self.host.clone()),
+            Err(e) => warn!("Failed to encode late join ack: {}", e),
+        }
+    }
+}
+
+/// Joins the match the room plays already once its host sent the first
+/// inputs, instead of waiting in the lobby for the next one.
+pub fn join_running_match(
+    mut commands: Commands,
+    mut state: ResMut<State<GameStage>>,
+    socket: Option<ResMut<Option<PeerSocket>>>,
+    mut config: ResMut<GameConfig>,
+    mut lobby: ResMut<LobbyState>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    mut logger: ResMut<Logger>,
+) {
+    let (host, packet) = match lobby.running_match.take() {
+        Some(running_match) => running_match,
+        None => return,
+    };
+
+    let replay = match LateJoinPacket::decode(&packet) {
+        // We only acked the first frame, later starts are stray resends
+        Some(LateJoinPacket::Inputs { start: 0, replay }) => replay,
+        Some(_) => return,
+        None => {
+            warn!("Invalid match inputs from {}", host);
+            return;
+        }
+    };
+
+    // Resent until we leave, a warning each time would flood the log
+    if !replay.compatible(definitions.hash(&hero_assets)) {
+        warn!("The running match of {} runs other heroes or version", host);
+        return;
+    }
+
+    let mut socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+    let socket = match socket.take() {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    logger.info("Joined the running match of ".to_string() + &host);
+
+    let previous_config = config.clone();
+    config.stocks = replay.stocks;
+    config.blast_zone = replay.blast_zone;
+
+    commands.insert_resource(MatchRoster {
+        heroes: replay.heroes.clone(),
+    });
+    commands.insert_resource(ManualStepping);
+    commands.insert_resource(LiveMatch {
+        socket,
+        host,
+        frames: replay.frames(),
+        accumulator: 0.0,
+        since_received: 0.0,
+        previous_config,
+    });
+
+    state.set(GameStage::Gameplay).unwrap();
+}
+
+/// Steps the live match with the inputs received so far: in real time once
+/// caught up, a few frames behind the host so it doesn't stall on every late
+/// packet.
+pub fn follow_match(world: &mut World) {
+    if !world.contains_resource::<LiveMatch>() {
+        return;
+    }
+
+    let delta = world.resource::<Time>().delta_seconds();
+    let frame = world.resource::<FrameCount>().frame;
+
+    world.resource_scope(|world, mut live: Mut<LiveMatch>| {
+        live.receive(delta);
+
+        if live.since_received > LIVE_MATCH_TIMEOUT_SECONDS {
+            let left = world
+                .resource_mut::<State<GameStage>>()
+                .set(GameStage::Lobby)
+                .is_ok();
+            if left {
+                world
+                    .resource_mut::<Logger>()
+                    .warn("Lost the connection to the match.".to_string());
+            }
+            return;
+        }
+
+        let frame_seconds = FRAME_DURATION.as_secs_f32();
+        live.accumulator += delta;
+        let mut frames = (live.accumulator / frame_seconds) as u32;
+        live.accumulator -= frames as f32 * frame_seconds;
+
+        let available = (live.frames.len() as u32).saturating_sub(frame);
+        if available > LIVE_BUFFER_FRAMES {
+            let catch_up = available - LIVE_BUFFER_FRAMES;
+            frames = frames.max(catch_up.min(MAX_CATCH_UP_FRAMES_PER_UPDATE));
+        }
+
+        // Time spent waiting for inputs isn't made up for afterwards
+        if frames >= available {
+            frames = available;
+            live.accumulator = 0.0;
+        }
+
+        for next in frame..frame + frames {
+            net::advance_frame(world, &live.frames[next as usize]);
+        }
+    });
+}
+
+/// Restores the rules the live match replaced and disconnects from its host.
+pub fn leave_live_match(
+    mut commands: Commands,
+    live: Option<Res<LiveMatch>>,
+    mut config: ResMut<GameConfig>,
+) {
+    if let Some(live) = live {
+        *config = live.previous_config.clone();
+        commands.remove_resource::<LiveMatch>();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packets_round_trip() {
+        let replay = ReplayRecorder::default().replay();
+        let packet = LateJoinPacket::Inputs { start: 120, replay };
+        match LateJoinPacket::decode(&packet.encode().unwrap()) {
+            Some(LateJoinPacket::Inputs { start, replay }) => {
+                assert_eq!(start, 120);
+                assert!(replay.compatible(0));
+                assert_eq!(replay.num_frames(), 0);
+            }
+            other => panic!("Decoded {:?}", other),
+        }
+
+        let ack = LateJoinPacket::Ack { frames: 7 }.encode().unwrap();
+        assert!(matches!(
+            LateJoinPacket::decode(&ack),
+            Some(LateJoinPacket::Ack { frames: 7 })
+        ));
+    }
+
+    #[test]
+    fn truncated_packets_are_rejected() {
+        let ack = LateJoinPacket::Ack { frames: 7 }.encode().unwrap();
+        for len in 0..ack.len() {
+            assert!(LateJoinPacket::decode(&ack[..len]).is_none());
+        }
+
+        let inputs = LateJoinPacket::Inputs {
+            start: 0,
+            replay: ReplayRecorder::default().replay(),
+        }
+        .encode()
+        .unwrap();
+        for len in 0..inputs.len() {
+            assert!(LateJoinPacket::decode(&inputs[..len]).is_none());
+        }
+    }
+}
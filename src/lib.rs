@@ -1,26 +1,56 @@
-use bevy::prelude::*;
+use bevy::{app::PluginGroupBuilder, prelude::*};
 use bevy_inspector_egui::*;
 use bevy_rapier2d::prelude::*;
 
+mod checksum;
+mod combat;
+mod conditions;
+mod config;
 mod debug_ui;
+mod desync;
 mod game;
+#[cfg(feature = "testing")]
+pub mod harness;
+mod headless;
+mod hero;
+mod hud;
+mod input;
+mod late_join;
+mod lobby;
+#[cfg(feature = "testing")]
+pub mod loopback;
 mod menu;
 mod net;
+mod physics;
 mod player;
+mod replay;
+mod spectator;
 
 use game::*;
 use player::*;
 
+pub use conditions::NetworkConditions;
+pub use config::GameConfig;
+pub use headless::InputScript;
+
 pub const LAUNCHER_TITLE: &str = "Dota Smash";
 
 const WINDOW_WIDTH: f32 = 1920.0;
 const WINDOW_HEIGHT: f32 = 1080.0;
 
-pub fn app() -> App {
+pub fn app(config: GameConfig) -> App {
     let mut app = App::new();
 
     // net::setup_ggrs(&mut app);
 
+    // Must be inserted before the `AssetPlugin` is built. Release builds keep
+    // their assets as loaded.
+    #[cfg(all(feature = "hot_reload", debug_assertions))]
+    app.insert_resource(bevy::asset::AssetServerSettings {
+        watch_for_changes: true,
+        ..Default::default()
+    });
+
     app.insert_resource(WindowDescriptor {
         title: LAUNCHER_TITLE.to_string(),
         width: WINDOW_WIDTH,
@@ -29,17 +59,56 @@ pub fn app() -> App {
         fit_canvas_to_parent: true,
         ..Default::default()
     })
+    .insert_resource(config)
     .add_plugins(DefaultPlugins)
     .add_plugin(debug_ui::DebugUiPlugin)
+    .add_plugin(hud::HudPlugin)
+    .add_plugin(spectator::SpectatorPlugin)
+    .add_plugin(input::ControlsPlugin)
     .add_plugin(WorldInspectorPlugin::new())
     .register_inspectable::<Player>()
-    .add_plugin(RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(1.0))
+    .add_plugins(SimulationPlugins)
     .add_plugin(RapierDebugRenderPlugin::default())
-    .add_plugin(GamePlugin)
-    // .add_plugin(menu::MenuPlugin)
+    .add_plugin(menu::MenuPlugin);
     // .add_startup_system(net::setup_socket)
     // .add_system(net::setup_session)
-    .run();
 
-    return app;
+    app
+}
+
+/// The game without window, renderer, input devices or UI: runs `frames`
+/// frames of a local match with idle players as fast as possible, then
+/// exits. For tests and servers. With a `loopback::LoopbackNetwork`
+/// resource it plays online against the other apps on the network instead.
+pub fn headless_app(config: GameConfig, frames: u32) -> App {
+    let mut app = App::new();
+
+    app.insert_resource(config)
+        .insert_resource(debug_ui::Logger::default())
+        // The lobby reads the controls, even with no device to press them
+        .insert_resource(input::InputSettings::default())
+        .add_plugins(MinimalPlugins)
+        .add_plugin(bevy::input::InputPlugin)
+        .add_plugin(AssetPlugin)
+        .add_plugin(TransformPlugin)
+        .add_plugin(HierarchyPlugin)
+        // Fireball sprites are built at runtime, even when never drawn
+        .add_asset::<TextureAtlas>()
+        .add_plugins(SimulationPlugins)
+        .add_plugin(headless::HeadlessPlugin { frames });
+
+    app
+}
+
+/// Heroes, physics and the match with its rollback schedule. Everything
+/// that decides what happens in a match, nothing that shows it.
+pub struct SimulationPlugins;
+
+impl PluginGroup for SimulationPlugins {
+    fn build(&mut self, group: &mut PluginGroupBuilder) {
+        group
+            .add(hero::HeroPlugin)
+            .add(physics::PhysicsPlugin)
+            .add(GamePlugin);
+    }
 }
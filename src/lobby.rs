@@ -0,0 +1,458 @@
+use std::collections::HashMap;
+
+use bevy::prelude::{Res, ResMut, SystemLabel, Time};
+use ggrs::PlayerType;
+
+use crate::{
+    config::{GameConfig, MIN_PLAYERS},
+    debug_ui::Logger,
+    desync::{PACKET_GGRS, PACKET_LATE_JOIN_INPUTS},
+    game::MatchMode,
+    hero::{Hero, HeroChoice, SelectedHero},
+    input::Controls,
+    net::{PeerSocket, INPUT_VERSION},
+};
+
+const MESSAGE_HERO_CHOICE: u8 = 0;
+const MESSAGE_READY: u8 = 1;
+const MESSAGE_HELLO: u8 = 2;
+const MESSAGE_SPECTATE: u8 = 3;
+const MESSAGE_ACK: u8 = 4;
+
+// The socket may drop any message, our state is sent again this often until
+// the peer acknowledged it
+const LOBBY_RESEND_SECONDS: f32 = 0.25;
+
+// Easy to read out loud, no 0/O or 1/I mixups
+const ROOM_CODE_ALPHABET: &[u8] = b"ABCDEFGHJKLMNPQRSTUVWXYZ23456789";
+pub const ROOM_CODE_LEN: usize = 5;
+
+/// Ordering of the lobby systems
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum LobbySystem {
+    ExchangeMessages,
+    JoinRunningMatch,
+}
+
+/// The matchbox room an online match is played in.
+#[derive(Clone, Debug, PartialEq, Eq, Default)]
+pub enum Room {
+    /// Matched with whoever else queues in the configured room
+    #[default]
+    Matchmaking,
+    /// Only players who know the code get in
+    Private(String),
+}
+
+impl Room {
+    /// A private room with a fresh code.
+    pub fn create() -> Room {
+        let code = (0..ROOM_CODE_LEN)
+            .map(|_| {
+                let index = fastrand::usize(..ROOM_CODE_ALPHABET.len());
+                ROOM_CODE_ALPHABET[index] as char
+            })
+            .collect();
+
+        Room::Private(code)
+    }
+
+    /// The private room of a code typed in by the player, `None` if the code
+    /// can't be valid.
+    pub fn join(code: &str) -> Option<Room> {
+        let code: String = code
+            .chars()
+            .filter(|c| !c.is_whitespace())
+            .map(|c| c.to_ascii_uppercase())
+            .collect();
+
+        let valid = code.len() == ROOM_CODE_LEN
+            && code.bytes().all(|c| ROOM_CODE_ALPHABET.contains(&c));
+
+        valid.then(|| Room::Private(code))
+    }
+
+    pub fn url(&self, config: &GameConfig) -> String {
+        match self {
+            Room::Matchmaking => config.room_url(),
+            Room::Private(code) => config.private_room_url(code),
+        }
+    }
+}
+
+/// Messages peers exchange over the socket before the GGRS session starts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LobbyMessage {
+    /// First message to a new peer, with our `INPUT_VERSION`
+    Hello { version: u8 },
+    HeroChoice(HeroChoice),
+    /// Sent instead of a hero choice by peers that only watch
+    Spectate,
+    /// A ready player can't take it back
+    Ready,
+    /// Answer to `Ready`: the sender knows everything about the ready peer
+    Ack,
+}
+
+impl LobbyMessage {
+    pub fn encode(&self) -> Box<[u8]> {
+        match self {
+            LobbyMessage::Hello { version } => {
+                Box::new([MESSAGE_HELLO, *version])
+            }
+            LobbyMessage::HeroChoice(choice) => Box::new([
+                MESSAGE_HERO_CHOICE,
+                choice.hero.index(),
+                choice.skin,
+            ]),
+            LobbyMessage::Spectate => Box::new([MESSAGE_SPECTATE]),
+            LobbyMessage::Ready => Box::new([MESSAGE_READY]),
+            LobbyMessage::Ack => Box::new([MESSAGE_ACK]),
+        }
+    }
+
+    pub fn decode(bytes: &[u8]) -> Option<LobbyMessage> {
+        match bytes {
+            [MESSAGE_HELLO, version] => {
+                Some(LobbyMessage::Hello { version: *version })
+            }
+            [MESSAGE_HERO_CHOICE, hero, skin] => {
+                Some(LobbyMessage::HeroChoice(HeroChoice {
+                    hero: Hero::from_index(*hero)?,
+                    skin: *skin,
+                }))
+            }
+            [MESSAGE_SPECTATE] => Some(LobbyMessage::Spectate),
+            [MESSAGE_READY] => Some(LobbyMessage::Ready),
+            [MESSAGE_ACK] => Some(LobbyMessage::Ack),
+            _ => None,
+        }
+    }
+}
+
+/// What we know about a peer in the room.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct PeerState {
+    /// `INPUT_VERSION` of the peer, once it said hello
+    pub version: Option<u8>,
+    pub hero: Option<HeroChoice>,
+    /// Watches instead of playing
+    pub spectator: bool,
+    pub ready: bool,
+    /// Whether the peer acknowledged that we are ready, and so knows all
+    /// about us
+    pub acked: bool,
+}
+
+impl PeerState {
+    /// Whether the peer simulates inputs the same way we do.
+    pub fn compatible(&self) -> bool {
+        self.version == Some(INPUT_VERSION)
+    }
+
+    /// Whether we heard everything from the peer: its version, whether it
+    /// plays or watches, and that it is ready.
+    pub fn complete(&self) -> bool {
+        self.version.is_some()
+            && (self.hero.is_some() || self.spectator)
+            && self.ready
+    }
+}
+
+/// State of the room we wait in for the match to start.
+#[derive(Default)]
+pub struct LobbyState {
+    /// Connected peers, by matchbox peer id
+    pub peers: HashMap<String, PeerState>,
+    /// Whether we marked ready
+    pub ready: bool,
+    /// Seconds since our state was last sent to the peers
+    since_sent: f32,
+    /// Latest inputs from the host of a match that runs already, with its
+    /// peer id. Only kept while we wait to spectate.
+    pub running_match: Option<(String, Box<[u8]>)>,
+}
+
+impl LobbyState {
+    /// Whether everyone is ready, we heard everything from every peer and
+    /// every peer heard everything from us. Until then the socket can't be
+    /// handed to GGRS.
+    pub fn all_ready(&self) -> bool {
+        self.ready
+            && self
+                .peers
+                .values()
+                .all(|peer| peer.complete() && peer.acked)
+    }
+
+    /// Players in the room, us included unless we spectate. Peers count as
+    /// players until they say they spectate.
+    pub fn num_players(&self, spectating: bool) -> usize {
+        let peers = self.peers.values().filter(|peer| !peer.spectator).count();
+        peers + usize::from(!spectating)
+    }
+
+    /// The players of the socket that play the match, in handle order.
+    /// Spectators are left out, including us if we spectate.
+    pub fn match_players(
+        &self,
+        players: Vec<PlayerType<String>>,
+        spectating: bool,
+    ) -> Vec<PlayerType<String>> {
+        players
+            .into_iter()
+            .filter(|player| match player {
+                PlayerType::Local => !spectating,
+                PlayerType::Remote(peer) => self
+                    .peers
+                    .get(peer)
+                    .map_or(true, |state| !state.spectator),
+                PlayerType::Spectator(_) => false,
+            })
+            .collect()
+    }
+
+    /// Peers watching the match, sorted so every peer lists them in the same
+    /// order.
+    pub fn spectators(&self) -> Vec<String> {
+        let mut spectators: Vec<String> = self
+            .peers
+            .iter()
+            .filter(|(_, state)| state.spectator)
+            .map(|(peer, _)| peer.clone())
+            .collect();
+        spectators.sort();
+        spectators
+    }
+}
+
+/// A player of a local versus match.
+#[derive(Clone, Copy, Debug)]
+pub struct LocalPlayer {
+    pub hero: HeroChoice,
+    pub controls: Controls,
+}
+
+impl LocalPlayer {
+    pub fn new(handle: usize) -> Self {
+        Self {
+            hero: HeroChoice::default(),
+            controls: Controls::for_handle(handle),
+        }
+    }
+}
+
+/// Players sharing this machine in a local versus match, indexed by player
+/// handle.
+pub struct LocalPlayers {
+    pub players: Vec<LocalPlayer>,
+}
+
+impl Default for LocalPlayers {
+    fn default() -> Self {
+        Self {
+            players: (0..MIN_PLAYERS).map(LocalPlayer::new).collect(),
+        }
+    }
+}
+
+/// Hero choice of every player in the match, indexed by player handle.
+#[derive(Default)]
+pub struct MatchRoster {
+    pub heroes: Vec<HeroChoice>,
+}
+
+impl MatchRoster {
+    /// Matches the choices to the handles GGRS will use. `None` while a peer
+    /// hasn't told us its choice yet, or if it runs another input version.
+    pub fn from_players(
+        players: &[PlayerType<String>],
+        local: HeroChoice,
+        lobby: &LobbyState,
+    ) -> Option<MatchRoster> {
+        let heroes = players
+            .iter()
+            .map(|player| match player {
+                PlayerType::Local => Some(local),
+                PlayerType::Remote(peer) => {
+                    let peer = lobby.peers.get(peer)?;
+                    if !peer.compatible() {
+                        return None;
+                    }
+                    peer.hero
+                }
+                PlayerType::Spectator(_) => None,
+            })
+            .collect::<Option<Vec<_>>>()?;
+
+        Some(MatchRoster { heroes })
+    }
+
+    /// All players are local, the config decides the heroes of the handles
+    /// not controlled by us.
+    pub fn local(
+        num_players: usize,
+        config: &GameConfig,
+        local: HeroChoice,
+    ) -> MatchRoster {
+        let heroes = (0..num_players)
+            .map(|handle| match config.heroes.get(handle) {
+                Some(hero) => HeroChoice {
+                    hero: *hero,
+                    skin: 0,
+                },
+                None => local,
+            })
+            .collect();
+
+        MatchRoster { heroes }
+    }
+
+    pub fn local_versus(local_players: &LocalPlayers) -> MatchRoster {
+        let heroes = local_players
+            .players
+            .iter()
+            .map(|player| player.hero)
+            .collect();
+
+        MatchRoster { heroes }
+    }
+
+    pub fn get(&self, handle: usize) -> HeroChoice {
+        self.heroes.get(handle).copied().unwrap_or_default()
+    }
+}
+
+pub fn reset_lobby_state(mut lobby: ResMut<LobbyState>) {
+    *lobby = LobbyState::default();
+}
+
+/// Tells peers who we play, or that we spectate, and whether we are ready,
+/// and collects the same from them. Spectators are ready right away.
+///
+/// The socket is unreliable, so our whole state is sent again and again
+/// until the peer acknowledges it, and we acknowledge theirs. A peer starts
+/// the session only once all of this went through both ways. Its first GGRS
+/// packets then stand in for an acknowledgement we may have missed.
+pub fn exchange_lobby_messages(
+    socket: Option<ResMut<Option<PeerSocket>>>,
+    selected_hero: Res<SelectedHero>,
+    mode: Res<MatchMode>,
+    time: Res<Time>,
+    mut lobby: ResMut<LobbyState>,
+    mut logger: ResMut<Logger>,
+) {
+    let mut socket = match socket {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    // Socket already handed to a session
+    let socket = match socket.as_mut() {
+        Some(socket) => socket,
+        None => return,
+    };
+
+    let spectating = *mode == MatchMode::Spectate;
+    if spectating {
+        lobby.ready = true;
+    }
+
+    let mut messages = vec![
+        LobbyMessage::Hello {
+            version: INPUT_VERSION,
+        },
+        if spectating {
+            LobbyMessage::Spectate
+        } else {
+            LobbyMessage::HeroChoice(selected_hero.0)
+        },
+    ];
+    if lobby.ready {
+        messages.push(LobbyMessage::Ready);
+    }
+
+    let send_state = |socket: &mut PeerSocket, peer: &str| {
+        for message in messages.iter() {
+            socket.send(message.encode(), peer.to_string());
+        }
+    };
+
+    for peer in socket.accept_new_connections() {
+        logger.info("Peer joined: ".to_string() + &peer);
+        send_state(socket, &peer);
+        lobby.peers.insert(peer, PeerState::default());
+    }
+
+    lobby.since_sent += time.delta_seconds();
+    if lobby.since_sent >= LOBBY_RESEND_SECONDS {
+        lobby.since_sent = 0.0;
+        for (peer, _) in lobby.peers.iter().filter(|(_, peer)| !peer.acked) {
+            send_state(socket, peer);
+        }
+    }
+
+    for (peer, packet) in socket.receive() {
+        match packet.first() {
+            Some(&PACKET_LATE_JOIN_INPUTS) if spectating => {
+                lobby.running_match = Some((peer, packet));
+                continue;
+            }
+            // Spectators asking for a running match
+            Some(&tag) if tag > PACKET_GGRS => continue,
+            _ => {}
+        }
+
+        let state = lobby.peers.entry(peer.clone()).or_default();
+
+        // The peer already started the session, it heard all from us
+        if packet.first() == Some(&PACKET_GGRS) {
+            state.acked = true;
+            continue;
+        }
+
+        match LobbyMessage::decode(&packet) {
+            Some(LobbyMessage::Hello { version }) => {
+                if state.version.is_none() && version != INPUT_VERSION {
+                    logger.warn(format!(
+                        "{} runs input version {}, we run {}. \
+                         Can't play together.",
+                        peer, version, INPUT_VERSION
+                    ));
+                }
+                state.version = Some(version);
+            }
+            Some(LobbyMessage::HeroChoice(choice)) => {
+                if state.hero.is_none() {
+                    logger.info(format!(
+                        "{} picked {}",
+                        peer,
+                        choice.hero.name()
+                    ));
+                }
+                state.hero = Some(choice);
+            }
+            Some(LobbyMessage::Spectate) => {
+                if !state.spectator {
+                    logger.info(format!("{} is spectating", peer));
+                }
+                state.spectator = true;
+            }
+            Some(LobbyMessage::Ready) => {
+                if !state.ready {
+                    logger.info(format!("{} is ready", peer));
+                }
+                state.ready = true;
+
+                // Our acks get lost too, every resend is answered
+                if state.complete() {
+                    socket.send(LobbyMessage::Ack.encode(), peer.clone());
+                }
+            }
+            Some(LobbyMessage::Ack) => state.acked = true,
+            None => {
+                logger.warn("Invalid lobby message from ".to_string() + &peer)
+            }
+        }
+    }
+}
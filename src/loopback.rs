@@ -0,0 +1,170 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+};
+
+use bevy::prelude::warn;
+use ggrs::{Message, NonBlockingSocket, PlayerType};
+
+use crate::{
+    checksum::StateHasher,
+    conditions::{DelayLine, NetworkConditions},
+};
+
+/// Stand-in for matchbox within one process: sockets made from the same
+/// network reach each other by address, no signaling server or WebRTC
+/// involved. For tests of the netcode, under artificial conditions if set.
+/// The same seed loses, duplicates and reorders the same packets of each
+/// address. Inserted as a resource, headless apps play online over it.
+#[derive(Clone, Default)]
+pub struct LoopbackNetwork {
+    inner: Arc<Mutex<LoopbackInner>>,
+}
+
+#[derive(Default)]
+struct LoopbackInner {
+    conditions: NetworkConditions,
+    seed: u64,
+    /// Packets on their way to each address, with the sender
+    inboxes: HashMap<String, DelayLine<(String, Box<[u8]>)>>,
+    /// Sockets handed out by `join`
+    joined: usize,
+}
+
+impl LoopbackNetwork {
+    pub fn new(conditions: NetworkConditions, seed: u64) -> Self {
+        let network = LoopbackNetwork::default();
+        network.inner.lock().unwrap().seed = seed;
+        network.set_conditions(conditions);
+        network
+    }
+
+    /// Applies to packets sent from now on, the ones in flight keep their
+    /// delay.
+    pub fn set_conditions(&self, conditions: NetworkConditions) {
+        self.inner.lock().unwrap().conditions = conditions;
+    }
+
+    /// A socket reachable at `addr`. Packets sent to addresses without a
+    /// socket are lost.
+    pub fn socket(&self, addr: &str) -> LoopbackSocket {
+        let mut inner = self.inner.lock().unwrap();
+
+        // Inboxes of their own seed, so their packets don't depend on the
+        // order the others receive in
+        let mut seed = StateHasher::new();
+        seed.write_u64(inner.seed);
+        seed.write_bytes(addr.as_bytes());
+        inner
+            .inboxes
+            .entry(addr.to_string())
+            .or_insert_with(|| DelayLine::with_seed(seed.finish()));
+        drop(inner);
+
+        LoopbackSocket {
+            addr: addr.to_string(),
+            peers: Vec::new(),
+            network: self.clone(),
+        }
+    }
+
+    /// A socket at a fresh address, like a peer joining a matchbox room.
+    pub fn join(&self) -> LoopbackSocket {
+        let joined = {
+            let mut inner = self.inner.lock().unwrap();
+            inner.joined += 1;
+            inner.joined
+        };
+
+        self.socket(&format!("peer-{}", joined))
+    }
+}
+
+/// Sends and receives the way `WebRtcSocket` does, and is a GGRS socket on
+/// its own.
+pub struct LoopbackSocket {
+    addr: String,
+    /// Other sockets of the network we know about
+    peers: Vec<String>,
+    network: LoopbackNetwork,
+}
+
+impl LoopbackSocket {
+    /// Sockets of the network we didn't know about yet.
+    pub fn accept_new_connections(&mut self) -> Vec<String> {
+        let inner = self.network.inner.lock().unwrap();
+        let mut new: Vec<String> = inner
+            .inboxes
+            .keys()
+            .filter(|addr| **addr != self.addr && !self.peers.contains(addr))
+            .cloned()
+            .collect();
+        new.sort();
+
+        self.peers.extend(new.iter().cloned());
+        new
+    }
+
+    /// Us and the known peers, sorted by address like matchbox does, so
+    /// every peer hands out the same player handles.
+    pub fn players(&self) -> Vec<PlayerType<String>> {
+        let mut addrs: Vec<&String> =
+            self.peers.iter().chain([&self.addr]).collect();
+        addrs.sort();
+
+        addrs
+            .into_iter()
+            .map(|addr| {
+                if *addr == self.addr {
+                    PlayerType::Local
+                } else {
+                    PlayerType::Remote(addr.clone())
+                }
+            })
+            .collect()
+    }
+
+    pub fn send(&mut self, packet: Box<[u8]>, addr: String) {
+        let mut inner = self.network.inner.lock().unwrap();
+        let conditions = inner.conditions;
+
+        if let Some(inbox) = inner.inboxes.get_mut(&addr) {
+            inbox.send(&conditions, (self.addr.clone(), packet));
+        }
+    }
+
+    pub fn receive(&mut self) -> Vec<(String, Box<[u8]>)> {
+        let mut inner = self.network.inner.lock().unwrap();
+        match inner.inboxes.get_mut(&self.addr) {
+            Some(inbox) => inbox.receive(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Leaving the network, later packets to us are lost.
+impl Drop for LoopbackSocket {
+    fn drop(&mut self) {
+        let mut inner = self.network.inner.lock().unwrap();
+        inner.inboxes.remove(&self.addr);
+    }
+}
+
+impl NonBlockingSocket<String> for LoopbackSocket {
+    fn send_to(&mut self, msg: &Message, addr: &String) {
+        match bincode::serialize(msg) {
+            Ok(bytes) => self.send(bytes.into_boxed_slice(), addr.clone()),
+            Err(e) => warn!("Failed to serialize GGRS message: {}", e),
+        }
+    }
+
+    fn receive_all_messages(&mut self) -> Vec<(String, Message)> {
+        self.receive()
+            .into_iter()
+            .filter_map(|(addr, packet)| {
+                let msg = bincode::deserialize(&packet).ok()?;
+                Some((addr, msg))
+            })
+            .collect()
+    }
+}
@@ -1,15 +1,643 @@
-use bevy::prelude::*;
-use bevy_egui::{egui::Window, EguiContext, EguiPlugin};
+use bevy::{app::AppExit, prelude::*};
+use bevy_egui::{
+    egui::{Button, ComboBox, Grid, Slider, TextEdit, Window},
+    EguiContext,
+};
 
+use crate::{
+    config::{GameConfig, MAX_PLAYERS, MIN_PLAYERS},
+    debug_ui::Logger,
+    game::{GameStage, MatchMode, MatchOutcome},
+    hero::{Hero, HeroDefinition, HeroDefinitions, SelectedHero},
+    input::{Action, Controls, InputSettings, KeyboardRegion},
+    lobby::{
+        LobbyState, LocalPlayer, LocalPlayers, MatchRoster, Room,
+        ROOM_CODE_LEN,
+    },
+    physics::ManualStepping,
+    replay::{
+        self, ReplayPlayer, ReplayRecorder, MAX_PLAYBACK_SPEED,
+        MIN_PLAYBACK_SPEED,
+    },
+};
+
+/// Menus between matches. Needs the `EguiPlugin`, which the `DebugUiPlugin`
+/// adds.
 pub struct MenuPlugin;
 
 impl Plugin for MenuPlugin {
     fn build(&self, app: &mut App) {
-        app.add_plugin(EguiPlugin).add_system(ui_example);
+        app.add_system_set(
+            SystemSet::on_update(GameStage::MainMenu).with_system(main_menu),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::Settings).with_system(settings),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::RoomSelect)
+                .with_system(room_select),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::HeroSelect)
+                .with_system(hero_select)
+                .with_system(local_versus_select),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::Lobby).with_system(lobby_window),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::MatchEnd)
+                .with_system(match_results),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::ReplayBrowser)
+                .with_system(replay_browser),
+        )
+        .add_system_set(
+            SystemSet::on_update(GameStage::ReplayViewer)
+                .with_system(replay_controls),
+        );
     }
 }
-fn ui_example(mut egui_context: ResMut<EguiContext>) {
-    Window::new("Hello").show(egui_context.ctx_mut(), |ui| {
-        ui.label("world");
+
+fn main_menu(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut mode: ResMut<MatchMode>,
+    mut exit: EventWriter<AppExit>,
+) {
+    Window::new("Dota Smash").show(egui_context.ctx_mut(), |ui| {
+        ui.vertical_centered_justified(|ui| {
+            if ui.button("Play Online").clicked() {
+                *mode = MatchMode::Online;
+                state.set(GameStage::RoomSelect).unwrap();
+            }
+            if ui.button("Local Versus").clicked() {
+                *mode = MatchMode::LocalVersus;
+                state.set(GameStage::HeroSelect).unwrap();
+            }
+            if ui.button("Training").clicked() {
+                *mode = MatchMode::Training;
+                state.set(GameStage::HeroSelect).unwrap();
+            }
+            // Replays are saved to and loaded from the file system
+            let replays = cfg!(not(target_arch = "wasm32"));
+            if ui
+                .add_enabled(replays, Button::new("Replays"))
+                .on_disabled_hover_text("Not available in the browser")
+                .clicked()
+            {
+                state.set(GameStage::ReplayBrowser).unwrap();
+            }
+            if ui.button("Settings").clicked() {
+                state.set(GameStage::Settings).unwrap();
+            }
+
+            // Browsers don't let a page close its own tab
+            if cfg!(not(target_arch = "wasm32")) && ui.button("Quit").clicked()
+            {
+                exit.send(AppExit);
+            }
+        });
+    });
+}
+
+/// Binding waiting for the next key or gamepad button press.
+#[derive(Clone, Copy)]
+enum Rebinding {
+    Key(KeyboardRegion, Action),
+    GamepadButton(Action),
+}
+
+fn settings(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut settings: ResMut<InputSettings>,
+    keyboard: Res<Input<KeyCode>>,
+    gamepad_buttons: Res<Input<GamepadButton>>,
+    mut region: Local<usize>,
+    mut rebinding: Local<Option<Rebinding>>,
+) {
+    match *rebinding {
+        Some(Rebinding::Key(region, action)) => {
+            if let Some(key) = keyboard.get_just_pressed().next() {
+                // Escape cancels
+                if *key != KeyCode::Escape {
+                    settings.keyboard_mut(region).keys.insert(action, *key);
+                }
+                *rebinding = None;
+            }
+        }
+        Some(Rebinding::GamepadButton(action)) => {
+            if let Some(button) = gamepad_buttons.get_just_pressed().next() {
+                settings.gamepad.buttons.insert(action, button.1);
+                *rebinding = None;
+            }
+            if keyboard.just_pressed(KeyCode::Escape) {
+                *rebinding = None;
+            }
+        }
+        None => (),
+    }
+
+    let selected_region = KeyboardRegion::ALL[*region];
+    let keys = settings.keyboard(selected_region);
+
+    Window::new("Settings").show(egui_context.ctx_mut(), |ui| {
+        ComboBox::from_label("Keyboard")
+            .selected_text(selected_region.name())
+            .show_ui(ui, |ui| {
+                for (index, option) in KeyboardRegion::ALL.iter().enumerate() {
+                    ui.selectable_value(&mut *region, index, option.name());
+                }
+            });
+
+        Grid::new("bindings").striped(true).show(ui, |ui| {
+            ui.label("Action");
+            ui.label("Key");
+            ui.label("Gamepad");
+            ui.end_row();
+
+            for action in Action::ALL {
+                ui.label(action.name());
+
+                let key = match *rebinding {
+                    Some(Rebinding::Key(_, a)) if a == action => {
+                        "Press a key...".to_string()
+                    }
+                    _ => match keys.keys.get(&action) {
+                        Some(key) => format!("{:?}", key),
+                        None => "-".to_string(),
+                    },
+                };
+                if ui.button(key).clicked() {
+                    *rebinding = Some(Rebinding::Key(selected_region, action));
+                }
+
+                let button = match *rebinding {
+                    Some(Rebinding::GamepadButton(a)) if a == action => {
+                        "Press a button...".to_string()
+                    }
+                    _ => match settings.gamepad.buttons.get(&action) {
+                        Some(button) => format!("{:?}", button),
+                        None => "-".to_string(),
+                    },
+                };
+                if ui.button(button).clicked() {
+                    *rebinding = Some(Rebinding::GamepadButton(action));
+                }
+
+                ui.end_row();
+            }
+        });
+
+        ui.add(
+            Slider::new(&mut settings.gamepad.deadzone, 0.0..=0.9)
+                .text("Stick deadzone"),
+        );
+
+        ui.separator();
+
+        if ui.button("Reset to defaults").clicked() {
+            *settings = InputSettings::default();
+        }
+        if ui.button("Back").clicked() {
+            settings.save();
+            *rebinding = None;
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+fn room_select(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut room: ResMut<Room>,
+    mut mode: ResMut<MatchMode>,
+    mut code: Local<String>,
+) {
+    Window::new("Play Online").show(egui_context.ctx_mut(), |ui| {
+        if ui.button("Quick Match").clicked() {
+            *room = Room::Matchmaking;
+            state.set(GameStage::HeroSelect).unwrap();
+        }
+        if ui.button("Create Room").clicked() {
+            *room = Room::create();
+            state.set(GameStage::HeroSelect).unwrap();
+        }
+
+        ui.separator();
+
+        let joined = Room::join(&code);
+        ui.horizontal(|ui| {
+            ui.add(
+                TextEdit::singleline(&mut *code)
+                    .hint_text("Room code")
+                    .char_limit(ROOM_CODE_LEN),
+            );
+            if ui.add_enabled(joined.is_some(), Button::new("Join")).clicked()
+            {
+                *room = joined.clone().unwrap();
+                state.set(GameStage::HeroSelect).unwrap();
+            }
+            // Spectators skip the hero select
+            if ui
+                .add_enabled(joined.is_some(), Button::new("Spectate"))
+                .on_hover_text("Watch the match of the room, or the next one")
+                .clicked()
+            {
+                *room = joined.unwrap();
+                *mode = MatchMode::Spectate;
+                state.set(GameStage::Lobby).unwrap();
+            }
+        });
+
+        if ui.button("Back").clicked() {
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+fn hero_select(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut selected_hero: ResMut<SelectedHero>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    mode: Res<MatchMode>,
+) {
+    // Every local player picks in `local_versus_select` instead
+    if *mode == MatchMode::LocalVersus {
+        return;
+    }
+
+    // Heroes can't be spawned before their definitions are loaded
+    let loaded = definitions.all_loaded(&hero_assets);
+
+    Window::new("Hero Select").show(egui_context.ctx_mut(), |ui| {
+        let choice = &mut selected_hero.0;
+
+        ui.heading("Hero");
+        for hero in Hero::ALL {
+            if ui.radio_value(&mut choice.hero, hero, hero.name()).changed() {
+                // Skins differ between heroes
+                choice.skin = 0;
+            }
+        }
+
+        if let Some(def) = definitions.get(choice.hero, &hero_assets) {
+            ui.heading("Skin");
+            for (index, skin) in def.skins.iter().enumerate() {
+                ui.radio_value(&mut choice.skin, index as u8, &skin.name);
+            }
+        }
+
+        if ui.add_enabled(loaded, Button::new("Ready")).clicked() {
+            state.set(GameStage::Lobby).unwrap();
+        }
+        if ui.button("Back").clicked() {
+            let previous = match *mode {
+                MatchMode::Online => GameStage::RoomSelect,
+                _ => GameStage::MainMenu,
+            };
+            state.set(previous).unwrap();
+        }
+    });
+}
+
+/// Heroes and controls of all players sharing this machine.
+fn local_versus_select(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut local_players: ResMut<LocalPlayers>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    gamepads: Res<Gamepads>,
+    mode: Res<MatchMode>,
+) {
+    if *mode != MatchMode::LocalVersus {
+        return;
+    }
+
+    let loaded = definitions.all_loaded(&hero_assets);
+
+    let mut controls = KeyboardRegion::ALL
+        .into_iter()
+        .map(Controls::Keyboard)
+        .collect::<Vec<_>>();
+    controls.extend(gamepads.iter().map(|gamepad| Controls::Gamepad(*gamepad)));
+
+    Window::new("Local Versus").show(egui_context.ctx_mut(), |ui| {
+        for (handle, player) in local_players.players.iter_mut().enumerate() {
+            ui.horizontal(|ui| {
+                ui.label(format!("P{}", handle + 1));
+
+                ComboBox::from_id_source(("hero", handle))
+                    .selected_text(player.hero.hero.name())
+                    .show_ui(ui, |ui| {
+                        for hero in Hero::ALL {
+                            let choice = &mut player.hero;
+                            if ui
+                                .selectable_value(
+                                    &mut choice.hero,
+                                    hero,
+                                    hero.name(),
+                                )
+                                .changed()
+                            {
+                                choice.skin = 0;
+                            }
+                        }
+                    });
+
+                let def = definitions.get(player.hero.hero, &hero_assets);
+                if let Some(def) = def {
+                    let skin = def
+                        .skin(player.hero.skin)
+                        .map(|skin| skin.name.as_str())
+                        .unwrap_or_default();
+
+                    ComboBox::from_id_source(("skin", handle))
+                        .selected_text(skin)
+                        .show_ui(ui, |ui| {
+                            for (index, skin) in def.skins.iter().enumerate() {
+                                ui.selectable_value(
+                                    &mut player.hero.skin,
+                                    index as u8,
+                                    &skin.name,
+                                );
+                            }
+                        });
+                }
+
+                ComboBox::from_id_source(("controls", handle))
+                    .selected_text(player.controls.name())
+                    .width(220.0)
+                    .show_ui(ui, |ui| {
+                        for option in controls.iter() {
+                            ui.selectable_value(
+                                &mut player.controls,
+                                *option,
+                                option.name(),
+                            );
+                        }
+                    });
+            });
+        }
+
+        ui.horizontal(|ui| {
+            let count = local_players.players.len();
+            if ui
+                .add_enabled(count < MAX_PLAYERS, Button::new("Add Player"))
+                .clicked()
+            {
+                local_players.players.push(LocalPlayer::new(count));
+            }
+            if ui
+                .add_enabled(count > MIN_PLAYERS, Button::new("Remove Player"))
+                .clicked()
+            {
+                local_players.players.pop();
+            }
+        });
+
+        ui.separator();
+
+        if ui.add_enabled(loaded, Button::new("Fight")).clicked() {
+            state.set(GameStage::Lobby).unwrap();
+        }
+        if ui.button("Back").clicked() {
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+/// Who is in the room and who is ready, while waiting for an online match.
+fn lobby_window(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut lobby: ResMut<LobbyState>,
+    room: Res<Room>,
+    selected_hero: Res<SelectedHero>,
+    mode: Res<MatchMode>,
+    config: Res<GameConfig>,
+) {
+    let online = matches!(*mode, MatchMode::Online | MatchMode::Spectate);
+    if !online || config.sync_test.is_some() {
+        return;
+    }
+    let spectating = *mode == MatchMode::Spectate;
+
+    Window::new("Lobby").show(egui_context.ctx_mut(), |ui| {
+        match &*room {
+            Room::Matchmaking => ui.label("Quick match"),
+            Room::Private(code) => ui.heading(format!("Room code: {}", code)),
+        };
+        let num_players = lobby.num_players(spectating);
+        ui.label(format!("Players: {}/{}", num_players, config.num_players));
+        if num_players > config.num_players {
+            ui.label("Room full, too many players for a match");
+        }
+
+        ui.separator();
+
+        let status = |ready: bool| if ready { "ready" } else { "not ready" };
+        if spectating {
+            ui.label("You: spectating");
+        } else {
+            ui.label(format!(
+                "You: {} ({})",
+                selected_hero.0.hero.name(),
+                status(lobby.ready)
+            ));
+        }
+        for (peer, peer_state) in lobby.peers.iter() {
+            let hero = match peer_state.hero {
+                Some(choice) => choice.hero.name(),
+                None if peer_state.spectator => "spectating",
+                None => "...",
+            };
+            let status = match peer_state.version {
+                Some(_) if !peer_state.compatible() => "incompatible version",
+                _ => status(peer_state.ready),
+            };
+            // Full peer ids are uuids, the start is enough to tell apart
+            let name: String = peer.chars().take(6).collect();
+            ui.label(format!("{}: {} ({})", name, hero, status));
+        }
+
+        ui.separator();
+
+        if spectating {
+            ui.label(
+                "Joins the match once all players are ready, \
+                 or right away if it already started",
+            );
+        } else if ui.add_enabled(!lobby.ready, Button::new("Ready")).clicked()
+        {
+            lobby.ready = true;
+        }
+        if ui.button("Leave").clicked() {
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+fn match_results(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    outcome: Res<MatchOutcome>,
+    mut recorder: ResMut<ReplayRecorder>,
+    mut logger: ResMut<Logger>,
+) {
+    Window::new("Results").show(egui_context.ctx_mut(), |ui| {
+        if outcome.has_winner {
+            ui.heading(format!("Player {} wins!", outcome.winner + 1));
+        } else {
+            ui.heading("Draw");
+        }
+
+        match &recorder.saved_as {
+            Some(name) => {
+                ui.label(format!("Replay saved as {}", name));
+            }
+            None => {
+                if ui.button("Save Replay").clicked() {
+                    match replay::save(&recorder.replay()) {
+                        Ok(name) => recorder.saved_as = Some(name),
+                        Err(e) => logger
+                            .warn(format!("Failed to save replay: {}", e)),
+                    }
+                }
+            }
+        }
+
+        if ui.button("Rematch").clicked() {
+            state.set(GameStage::Rematch).unwrap();
+        }
+        if ui.button("Main Menu").clicked() {
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+fn replay_browser(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut commands: Commands,
+    mut config: ResMut<GameConfig>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    mut logger: ResMut<Logger>,
+    // Listed once per visit rather than every frame
+    mut replays: Local<Option<Vec<String>>>,
+) {
+    // Heroes can't be spawned before their definitions are loaded
+    let loaded = definitions.all_loaded(&hero_assets);
+    let heroes_hash = definitions.hash(&hero_assets);
+
+    Window::new("Replays").show(egui_context.ctx_mut(), |ui| {
+        let names = replays.get_or_insert_with(replay::saved_replays);
+        if names.is_empty() {
+            ui.label("No saved replays");
+        }
+
+        let mut picked = None;
+        for name in names.iter() {
+            if ui.add_enabled(loaded, Button::new(name)).clicked() {
+                picked = Some(name.clone());
+            }
+        }
+
+        if let Some(name) = picked {
+            match replay::load(&name) {
+                Ok(replay) if replay.compatible(heroes_hash) => {
+                    commands.insert_resource(MatchRoster {
+                        heroes: replay.heroes.clone(),
+                    });
+                    commands.insert_resource(ReplayPlayer::start(
+                        replay,
+                        &mut config,
+                    ));
+                    commands.insert_resource(ManualStepping);
+                    *replays = None;
+                    state.set(GameStage::ReplayViewer).unwrap();
+                    return;
+                }
+                Ok(_) => logger.warn(format!(
+                    "{} was recorded with another version of the game \
+                     or its heroes",
+                    name
+                )),
+                Err(e) => {
+                    logger.warn(format!("Failed to load {}: {}", name, e))
+                }
+            }
+        }
+
+        if ui.button("Back").clicked() {
+            *replays = None;
+            state.set(GameStage::MainMenu).unwrap();
+        }
+    });
+}
+
+fn replay_controls(
+    mut egui_context: ResMut<EguiContext>,
+    mut state: ResMut<State<GameStage>>,
+    mut commands: Commands,
+    mut config: ResMut<GameConfig>,
+    player: Option<ResMut<ReplayPlayer>>,
+) {
+    let mut player = match player {
+        Some(player) => player,
+        None => return,
+    };
+
+    Window::new("Replay").show(egui_context.ctx_mut(), |ui| {
+        let num_frames = player.num_frames();
+
+        ui.horizontal(|ui| {
+            let play = if player.paused { "Play" } else { "Pause" };
+            if ui.button(play).clicked() {
+                // Playing a finished replay starts it over
+                if player.paused && player.frame >= num_frames {
+                    player.seek(0);
+                }
+                player.paused = !player.paused;
+            }
+            if ui
+                .add_enabled(player.paused, Button::new("Step"))
+                .clicked()
+            {
+                player.step();
+            }
+        });
+
+        let mut speed = player.speed;
+        ui.add(
+            Slider::new(&mut speed, MIN_PLAYBACK_SPEED..=MAX_PLAYBACK_SPEED)
+                .text("Speed"),
+        );
+        player.speed = speed;
+
+        let mut frame = player.frame;
+        let seek = ui.add_enabled(
+            !player.seeking(),
+            Slider::new(&mut frame, 0..=num_frames).text("Frame"),
+        );
+        if seek.changed() {
+            player.seek(frame);
+        }
+        if player.seeking() {
+            ui.label("Seeking...");
+        }
+
+        if ui.button("Exit").clicked() {
+            player.stop(&mut config);
+            commands.remove_resource::<ReplayPlayer>();
+            commands.remove_resource::<ManualStepping>();
+            state.set(GameStage::MainMenu).unwrap();
+        }
     });
 }
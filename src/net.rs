@@ -1,29 +1,105 @@
+use std::collections::HashMap;
+
 use bevy::{
     prelude::{
-        info, App, Commands, Component, ResMut, Schedule, SystemStage,
-        Transform,
+        info, App, Commands, Component, EventReader, EventWriter, Mut,
+        ParallelSystemDescriptorCoercion, Res, ResMut, Schedule, State,
+        SystemLabel, SystemStage, Transform, Vec2, World,
     },
     reflect::Reflect,
     tasks::IoTaskPool,
 };
 use bevy_ggrs::{GGRSPlugin, SessionType};
-use bevy_rapier2d::prelude::Velocity;
+use bevy_rapier2d::prelude::{PhysicsStages, Velocity};
 use bytemuck::{Pod, Zeroable};
-use ggrs::{Config, NetworkStats, P2PSession, SessionBuilder};
+use ggrs::{
+    Config, GGRSEvent, InputStatus, NetworkStats, P2PSession, PlayerType,
+    SessionBuilder, SpectatorSession, SyncTestSession,
+};
 use matchbox_socket::WebRtcSocket;
+use serde::{Deserialize, Serialize};
 
 use crate::{
+    checksum::{self, ChecksumHistory, StateChecksum, ROLLBACK_CHECKSUM},
+    conditions::NetworkSimulator,
+    config::GameConfig,
     debug_ui::Logger,
-    game::{self, Fireball, GameStage, GameState, FPS, ROLLBACK_DEFAULT},
-    player::{self, Player},
+    desync::{DesyncDetector, SessionChannel, SessionSocket},
+    game::{
+        self, Fireball, FireballAnimationTimer, FireballLiveTimer, GameStage,
+        MatchMode, MatchOutcome, FPS, ROLLBACK_DEFAULT,
+    },
+    hero::SelectedHero,
+    late_join::LateSpectators,
+    lobby::{LobbyState, LocalPlayers, MatchRoster, Room},
+    physics::{
+        self, ManualStepping, PhysicsSnapshot, ROLLBACK_PHYSICS_RESTORE,
+        ROLLBACK_PHYSICS_SNAPSHOT,
+    },
+    replay::{self, ReplayRecorder},
+    player::{self, AbilityCooldowns, JumpState, Player},
 };
 
-const ROOM_URL: &str = "ws://192.168.2.170:3536/next_2";
+#[cfg(feature = "testing")]
+use crate::loopback::{LoopbackNetwork, LoopbackSocket};
+
+// How long a wait recommendation is shown
+const WAIT_INDICATOR_FRAMES: u32 = 120;
+// The player and the training dummy
+const TRAINING_PLAYERS: usize = 2;
+
+/// Bumped whenever `BoxInput`, how inputs are simulated or the packets of a
+/// session change. Peers exchange it in the lobby and refuse to play with
+/// other versions.
+pub const INPUT_VERSION: u8 = 2;
+
+/// Frames GGRS may run ahead of the confirmed inputs, the furthest a
+/// rollback reaches back.
+pub const MAX_PREDICTION_FRAMES: usize = 12;
 
+/// Input of one player for one frame, as sent to the peers. Padding free, so
+/// it can be sent as raw bytes.
 #[repr(C)]
-#[derive(Copy, Clone, PartialEq, Pod, Zeroable)]
+#[derive(
+    Copy, Clone, PartialEq, Debug, Pod, Zeroable, Serialize, Deserialize,
+)]
 pub struct BoxInput {
-    pub inp: u8,
+    /// `player::INPUT_*` bits
+    pub buttons: u16,
+    /// Left stick, quantized to -127..=127
+    pub stick_x: i8,
+    pub stick_y: i8,
+    /// `INPUT_VERSION` of the sender
+    pub version: u8,
+    pub reserved: u8,
+}
+
+impl BoxInput {
+    pub fn new(buttons: u16, stick: Vec2) -> Self {
+        let quantize = |value: f32| (value.clamp(-1.0, 1.0) * 127.0) as i8;
+
+        Self {
+            buttons,
+            stick_x: quantize(stick.x),
+            stick_y: quantize(stick.y),
+            version: INPUT_VERSION,
+            reserved: 0,
+        }
+    }
+
+    /// Pressed buttons. Inputs of another version read as nothing pressed,
+    /// as do the zeroed inputs GGRS uses before the first real input.
+    pub fn buttons(&self) -> u16 {
+        if self.version == INPUT_VERSION {
+            self.buttons
+        } else {
+            0
+        }
+    }
+
+    pub fn stick(&self) -> (i8, i8) {
+        (self.stick_x, self.stick_y)
+    }
 }
 
 #[derive(Debug)]
@@ -33,16 +109,114 @@ impl Config for GGRSConfig {
     type State = u8;
     type Address = String;
 }
+
+/// Ordering of systems within the rollback schedule
+#[derive(SystemLabel, Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RollbackSystem {
+    MovePlayers,
+    Fireballs,
+    IncreaseFrame,
+}
 #[derive(Default, Reflect, Hash, Component)]
 #[reflect(Hash)]
 pub struct FrameCount {
     pub frame: u32,
 }
 
+/// The socket we talk to the peers of the room over, in the lobby and
+/// during a session.
+pub enum PeerSocket {
+    WebRtc(WebRtcSocket),
+    /// Peers within the same process, for tests
+    #[cfg(feature = "testing")]
+    Loopback(LoopbackSocket),
+}
+
+impl PeerSocket {
+    /// Peers that connected since the last call.
+    pub fn accept_new_connections(&mut self) -> Vec<String> {
+        match self {
+            PeerSocket::WebRtc(socket) => socket.accept_new_connections(),
+            #[cfg(feature = "testing")]
+            PeerSocket::Loopback(socket) => socket.accept_new_connections(),
+        }
+    }
+
+    /// Us and the connected peers, in the same order on every peer.
+    pub fn players(&self) -> Vec<PlayerType<String>> {
+        match self {
+            PeerSocket::WebRtc(socket) => socket.players(),
+            #[cfg(feature = "testing")]
+            PeerSocket::Loopback(socket) => socket.players(),
+        }
+    }
+
+    pub fn send(&mut self, packet: Box<[u8]>, peer: String) {
+        match self {
+            PeerSocket::WebRtc(socket) => socket.send(packet, peer),
+            #[cfg(feature = "testing")]
+            PeerSocket::Loopback(socket) => socket.send(packet, peer),
+        }
+    }
+
+    pub fn receive(&mut self) -> Vec<(String, Box<[u8]>)> {
+        match self {
+            PeerSocket::WebRtc(socket) => socket.receive(),
+            #[cfg(feature = "testing")]
+            PeerSocket::Loopback(socket) => socket.receive(),
+        }
+    }
+}
+
+/// GGRS session events, re-sent as Bevy events with the peer's matchbox id.
+#[derive(Debug, Clone)]
+pub enum SessionEvent {
+    Synchronizing { peer: String, total: u32, count: u32 },
+    Synchronized { peer: String },
+    NetworkInterrupted { peer: String, disconnect_timeout: u128 },
+    NetworkResumed { peer: String },
+    Disconnected { peer: String },
+    WaitRecommendation { skip_frames: u32 },
+}
+
+/// Connection state of the running session, for the on-screen indicators.
+#[derive(Default)]
+pub struct ConnectionStatus {
+    /// Peers still synchronizing, with (count, total) progress
+    pub synchronizing: HashMap<String, (u32, u32)>,
+    /// Peers we haven't heard from, with their disconnect timeout in ms
+    pub interrupted: HashMap<String, u128>,
+    /// Frames GGRS recommends to skip so the peers can catch up
+    pub wait_frames: u32,
+    /// Remaining frames the wait recommendation stays on screen
+    pub wait_indicator_frames: u32,
+}
+
 pub fn setup_ggrs(mut app: &mut App) {
+    let simulator = app
+        .world
+        .get_resource::<GameConfig>()
+        .and_then(|config| config.network_seed)
+        .map_or_else(NetworkSimulator::default, NetworkSimulator::with_seed);
+
     app.insert_resource(FrameCount { frame: 0 })
+        .insert_resource(StateChecksum::default())
+        .insert_resource(ChecksumHistory::default())
         .insert_resource(NetworkStats::default())
-        .add_system(update_networking_stats);
+        .insert_resource(ConnectionStatus::default())
+        .insert_resource(Room::default())
+        .insert_resource(LobbyState::default())
+        .insert_resource(LocalPlayers::default())
+        .insert_resource(MatchRoster::default())
+        .insert_resource(ReplayRecorder::default())
+        .insert_resource(DesyncDetector::default())
+        .insert_resource(LateSpectators::default())
+        .insert_resource(simulator)
+        .insert_resource(ManualSchedule(rollback_schedule()))
+        .add_event::<SessionEvent>()
+        .add_system(update_networking_stats)
+        .add_system(poll_session_events)
+        .add_system(update_connection_status);
 
     GGRSPlugin::<GGRSConfig>::new()
         .with_update_frequency(game::FPS as usize)
@@ -50,19 +224,97 @@ pub fn setup_ggrs(mut app: &mut App) {
         .register_rollback_type::<Transform>()
         .register_rollback_type::<Velocity>()
         .register_rollback_type::<Player>()
+        .register_rollback_type::<AbilityCooldowns>()
+        .register_rollback_type::<JumpState>()
         .register_rollback_type::<Fireball>()
+        .register_rollback_type::<FireballLiveTimer>()
+        .register_rollback_type::<FireballAnimationTimer>()
         .register_rollback_type::<FrameCount>()
-        .with_rollback_schedule(
-            Schedule::default().with_stage(
-                ROLLBACK_DEFAULT,
-                SystemStage::parallel()
-                    .with_system(player::ggrs_move_player_system)
-                    .with_system(increase_frame_system),
-            ),
-        )
+        .register_rollback_type::<MatchOutcome>()
+        .register_rollback_type::<StateChecksum>()
+        .register_rollback_type::<PhysicsSnapshot>()
+        .with_rollback_schedule(rollback_schedule())
         .build(&mut app);
 }
 
+/// Systems simulating one frame of a match. GGRS runs them for a session,
+/// the replay viewer for a replay.
+pub fn rollback_schedule() -> Schedule {
+    Schedule::default()
+        .with_stage(
+            ROLLBACK_PHYSICS_RESTORE,
+            SystemStage::single(physics::restore_physics_snapshot),
+        )
+        .with_stage_after(
+            ROLLBACK_PHYSICS_RESTORE,
+            ROLLBACK_DEFAULT,
+            SystemStage::parallel()
+                .with_system(
+                    player::ggrs_move_player_system
+                        .label(RollbackSystem::MovePlayers),
+                )
+                .with_system(
+                    game::fireball_system
+                        .label(RollbackSystem::Fireballs)
+                        .after(RollbackSystem::MovePlayers),
+                )
+                .with_system(
+                    game::ko_system.after(RollbackSystem::Fireballs),
+                )
+                .with_system(game::animate_fireball_system)
+                .with_system(
+                    replay::record_inputs.before(RollbackSystem::IncreaseFrame),
+                )
+                .with_system(
+                    increase_frame_system.label(RollbackSystem::IncreaseFrame),
+                ),
+        )
+        // Physics steps on the velocities set by the game systems
+        .with_stage_after(
+            ROLLBACK_DEFAULT,
+            PhysicsStages::SyncBackend,
+            physics::rollback_stage(PhysicsStages::SyncBackend),
+        )
+        .with_stage_after(
+            PhysicsStages::SyncBackend,
+            PhysicsStages::StepSimulation,
+            physics::rollback_stage(PhysicsStages::StepSimulation),
+        )
+        .with_stage_after(
+            PhysicsStages::StepSimulation,
+            PhysicsStages::Writeback,
+            physics::rollback_stage(PhysicsStages::Writeback),
+        )
+        .with_stage_after(
+            PhysicsStages::Writeback,
+            ROLLBACK_PHYSICS_SNAPSHOT,
+            SystemStage::single(physics::save_physics_snapshot),
+        )
+        .with_stage_after(
+            ROLLBACK_PHYSICS_SNAPSHOT,
+            ROLLBACK_CHECKSUM,
+            SystemStage::single(checksum::checksum_system),
+        )
+}
+
+/// A second instance of the rollback schedule, advanced by `advance_frame`
+/// instead of a GGRS session. Used by the replay viewer and headless runs.
+pub struct ManualSchedule(Schedule);
+
+/// Simulates one frame with the given input for every player handle.
+pub fn advance_frame(world: &mut World, inputs: &[BoxInput]) {
+    let inputs: Vec<(BoxInput, InputStatus)> = inputs
+        .iter()
+        .map(|input| (*input, InputStatus::Confirmed))
+        .collect();
+
+    world.insert_resource(inputs);
+    world.resource_scope(|world, mut schedule: Mut<ManualSchedule>| {
+        schedule.0.run_once(world);
+    });
+    world.remove_resource::<Vec<(BoxInput, InputStatus)>>();
+}
+
 fn update_networking_stats(
     mut network_status: ResMut<NetworkStats>,
     session: Option<ResMut<P2PSession<GGRSConfig>>>,
@@ -92,31 +344,223 @@ fn update_networking_stats(
     }
 }
 
-pub fn setup_socket(mut commands: Commands, mut game_state: ResMut<GameState>) {
-    if game_state.stage != GameStage::SetupSocket {
-        return; // Nothing to do we are not in the init phase
+fn poll_session_events(
+    session: Option<ResMut<P2PSession<GGRSConfig>>>,
+    spectator_session: Option<ResMut<SpectatorSession<GGRSConfig>>>,
+    mut session_events: EventWriter<SessionEvent>,
+    mut logger: ResMut<Logger>,
+) {
+    let events: Vec<GGRSEvent<GGRSConfig>> = match (session, spectator_session)
+    {
+        (Some(mut session), _) => session.events().collect(),
+        (_, Some(mut session)) => session.events().collect(),
+        (None, None) => return,
+    };
+
+    for event in events {
+        let event = match event {
+            GGRSEvent::Synchronizing { addr, total, count } => {
+                // A handful of roundtrips per peer, few enough to log each
+                logger.info(format!(
+                    "Synchronizing with {}: {}/{}",
+                    addr, count, total
+                ));
+                SessionEvent::Synchronizing {
+                    peer: addr,
+                    total,
+                    count,
+                }
+            }
+            GGRSEvent::Synchronized { addr } => {
+                logger.info("Synchronized with ".to_string() + &addr);
+                SessionEvent::Synchronized { peer: addr }
+            }
+            GGRSEvent::NetworkInterrupted {
+                addr,
+                disconnect_timeout,
+            } => {
+                logger.warn(format!(
+                    "Connection to {} interrupted, disconnecting in {}ms",
+                    addr, disconnect_timeout
+                ));
+                SessionEvent::NetworkInterrupted {
+                    peer: addr,
+                    disconnect_timeout,
+                }
+            }
+            GGRSEvent::NetworkResumed { addr } => {
+                logger.info("Connection resumed: ".to_string() + &addr);
+                SessionEvent::NetworkResumed { peer: addr }
+            }
+            GGRSEvent::Disconnected { addr } => {
+                logger.warn("Peer disconnected: ".to_string() + &addr);
+                SessionEvent::Disconnected { peer: addr }
+            }
+            GGRSEvent::WaitRecommendation { skip_frames } => {
+                logger.info(format!(
+                    "Running ahead of peers, wait {} frames",
+                    skip_frames
+                ));
+                SessionEvent::WaitRecommendation { skip_frames }
+            }
+        };
+
+        session_events.send(event);
+    }
+}
+
+fn update_connection_status(
+    mut session_events: EventReader<SessionEvent>,
+    mut status: ResMut<ConnectionStatus>,
+) {
+    status.wait_indicator_frames =
+        status.wait_indicator_frames.saturating_sub(1);
+
+    for event in session_events.iter() {
+        match event {
+            SessionEvent::Synchronizing { peer, total, count } => {
+                status.synchronizing.insert(peer.clone(), (*count, *total));
+            }
+            SessionEvent::Synchronized { peer } => {
+                status.synchronizing.remove(peer);
+            }
+            SessionEvent::NetworkInterrupted {
+                peer,
+                disconnect_timeout,
+            } => {
+                status.interrupted.insert(peer.clone(), *disconnect_timeout);
+            }
+            SessionEvent::NetworkResumed { peer } => {
+                status.interrupted.remove(peer);
+            }
+            SessionEvent::Disconnected { peer } => {
+                status.synchronizing.remove(peer);
+                status.interrupted.remove(peer);
+            }
+            SessionEvent::WaitRecommendation { skip_frames } => {
+                status.wait_frames = *skip_frames;
+                status.wait_indicator_frames = WAIT_INDICATOR_FRAMES;
+            }
+        }
     }
+}
 
-    info!("Connecting to matchbox server: {:?}", ROOM_URL);
+/// A peer left for good, the match can't continue without it.
+pub fn return_to_lobby_on_disconnect(
+    mut session_events: EventReader<SessionEvent>,
+    mut state: ResMut<State<GameStage>>,
+    mut logger: ResMut<Logger>,
+) {
+    let disconnected = session_events
+        .iter()
+        .any(|event| matches!(event, SessionEvent::Disconnected { .. }));
 
-    let (socket, message_loop) = WebRtcSocket::new(ROOM_URL);
+    // The match may have ended in the same frame, its results are shown then
+    if disconnected && state.set(GameStage::Lobby).is_ok() {
+        logger.info("Match aborted, returning to lobby.".to_string());
+    }
+}
+
+pub fn setup_socket(
+    mut commands: Commands,
+    config: Res<GameConfig>,
+    mode: Res<MatchMode>,
+    room: Res<Room>,
+    #[cfg(feature = "testing")] loopback: Option<Res<LoopbackNetwork>>,
+    mut logger: ResMut<Logger>,
+) {
+    // Training and sync tests run all players locally, no peers to connect to
+    let online = matches!(*mode, MatchMode::Online | MatchMode::Spectate);
+    if !online || config.sync_test.is_some() {
+        return;
+    }
+
+    // The network stands in for the room
+    #[cfg(feature = "testing")]
+    if let Some(network) = loopback {
+        let socket = PeerSocket::Loopback(network.join());
+        commands.insert_resource(Some(socket));
+        return;
+    }
+
+    let room_url = room.url(&config);
+
+    info!("Connecting to matchbox server: {:?}", room_url);
+    match &*room {
+        Room::Matchmaking => {
+            logger.info("Joining room: ".to_string() + &config.room)
+        }
+        Room::Private(code) => {
+            logger.info("Joining private room: ".to_string() + code)
+        }
+    }
+
+    let (socket, message_loop) = WebRtcSocket::new(room_url);
 
     // The message loop needs to be awaited, or nothing will happen.
     // We do this here using bevy's task system.
     IoTaskPool::get().spawn(message_loop).detach();
 
-    commands.insert_resource(Some(socket));
+    commands.insert_resource(Some(PeerSocket::WebRtc(socket)));
+}
 
-    game_state.stage = GameStage::SetupSession;
+/// Drops the socket if we leave the lobby before a session took it over.
+pub fn close_socket(mut commands: Commands) {
+    commands.remove_resource::<Option<PeerSocket>>();
 }
 
 pub fn setup_session(
     mut commands: Commands,
-    mut game_state: ResMut<GameState>,
-    socket: Option<ResMut<Option<WebRtcSocket>>>,
+    mut state: ResMut<State<GameStage>>,
+    socket: Option<ResMut<Option<PeerSocket>>>,
+    config: Res<GameConfig>,
+    mode: Res<MatchMode>,
+    selected_hero: Res<SelectedHero>,
+    lobby: Res<LobbyState>,
+    local_players: Res<LocalPlayers>,
+    mut detector: ResMut<DesyncDetector>,
+    mut late_spectators: ResMut<LateSpectators>,
+    simulator: Res<NetworkSimulator>,
     mut logger: ResMut<Logger>,
 ) {
-    if game_state.stage != GameStage::SetupSession {
+    // Offline modes go through the same rollback schedule as online matches,
+    // just with all handles local
+    let local_roster = match *mode {
+        MatchMode::Training => Some(MatchRoster::local(
+            TRAINING_PLAYERS,
+            &config,
+            selected_hero.0,
+        )),
+        MatchMode::LocalVersus => {
+            Some(MatchRoster::local_versus(&local_players))
+        }
+        MatchMode::Online | MatchMode::Spectate => None,
+    };
+
+    if let Some(roster) = local_roster {
+        // Without rollbacks unless a sync test was asked for
+        let check_distance = config.sync_test.unwrap_or(0);
+        let num_players = roster.heroes.len();
+        start_local_session(&mut commands, num_players, check_distance);
+        commands.insert_resource(roster);
+        logger.info(format!("Local match started, {:?}", *mode));
+        state.set(GameStage::Gameplay).unwrap();
+        return;
+    }
+
+    if let Some(check_distance) = config.sync_test {
+        let num_players = config.num_players;
+        start_local_session(&mut commands, num_players, check_distance);
+        commands.insert_resource(MatchRoster::local(
+            num_players,
+            &config,
+            selected_hero.0,
+        ));
+        logger.info(
+            "Sync test session started, check distance: ".to_string()
+                + &check_distance.to_string(),
+        );
+        state.set(GameStage::Gameplay).unwrap();
         return;
     }
 
@@ -128,41 +572,173 @@ pub fn setup_session(
     let mut socket = socket.unwrap();
     let socket = socket.as_mut();
 
-    // Check for new connections
-    socket.as_mut().unwrap().accept_new_connections();
+    // Socket already handed to a session
+    if socket.is_none() {
+        return;
+    }
 
-    let players = socket.as_ref().unwrap().players();
+    // New connections are accepted by `lobby::exchange_lobby_messages`
+    let spectating = *mode == MatchMode::Spectate;
+    let players = lobby
+        .match_players(socket.as_ref().unwrap().players(), spectating);
 
-    let num_players = 2;
-    if players.len() < num_players {
-        return; // wait for more playere
+    // Private rooms let in anyone with the code, with too many players the
+    // lobby shows the room as full instead of starting
+    let num_players = config.num_players;
+    if players.len() != num_players {
+        return;
     }
 
+    // Wait until every peer told us which hero it plays and is ready
+    let local = selected_hero.0;
+    let roster = match MatchRoster::from_players(&players, local, &lobby) {
+        Some(roster) if lobby.all_ready() => roster,
+        _ => return,
+    };
+
     info!("All peers have joined, going in-game");
     // consume the socket (currently required because GGRS takes ownership of its socket)
     let socket = socket.take().unwrap();
 
-    // create a GGRS P2P session
+    // Spectators only watch, there is nothing to compare with them
+    let remote_players = players
+        .iter()
+        .filter_map(|player| match player {
+            PlayerType::Remote(peer) if !spectating => Some(peer.clone()),
+            _ => None,
+        })
+        .collect();
+    let channel = SessionChannel::default();
+    detector.start(remote_players, &channel);
+    let socket = SessionSocket::new(socket, &channel, &simulator);
+
     let mut session_builder = SessionBuilder::<GGRSConfig>::new()
         .with_num_players(num_players)
-        .with_max_prediction_window(12)
+        .with_max_prediction_window(MAX_PREDICTION_FRAMES)
         .with_fps(FPS as usize)
         .expect("Invalid FPS")
         .with_input_delay(2);
 
-    for (i, player_type) in socket.players().iter().enumerate() {
+    // The player with the first handle hosts the spectators
+    let host = match players.first() {
+        Some(PlayerType::Remote(peer)) => Some(peer.clone()),
+        _ => None,
+    };
+
+    if spectating {
+        let host = host.expect("Spectators have a remote host");
+        logger.info("Spectating, inputs from ".to_string() + &host);
+
+        let session = session_builder.start_spectator_session(host, socket);
+
+        commands.insert_resource(session);
+        commands.insert_resource(SessionType::SpectatorSession);
+        commands.insert_resource(ManualStepping);
+        commands.insert_resource(roster);
+
+        state.set(GameStage::Gameplay).unwrap();
+        return;
+    }
+
+    // create a GGRS P2P session
+    for (i, player_type) in players.into_iter().enumerate() {
         session_builder = session_builder
-            .add_player(player_type.clone(), i)
+            .add_player(player_type, i)
             .expect("Invalid player added.");
     }
 
+    // Spectator handles follow the player handles
+    if host.is_none() {
+        for (i, peer) in lobby.spectators().into_iter().enumerate() {
+            session_builder = session_builder
+                .add_player(PlayerType::Spectator(peer), num_players + i)
+                .expect("Invalid spectator added.");
+        }
+    }
+
+    // The host of the spectators also takes the ones joining later
+    late_spectators.start(&channel, host.is_none());
+
     // start the GGRS session
     let session = session_builder.start_p2p_session(socket).unwrap();
 
     commands.insert_resource(session);
     commands.insert_resource(SessionType::P2PSession);
+    commands.insert_resource(ManualStepping);
+    commands.insert_resource(roster);
 
-    game_state.stage = GameStage::SetupGameplayPlayers;
+    state.set(GameStage::Gameplay).unwrap();
+}
+
+/// Runs all players on this machine in a sync test session. A check distance
+/// of zero never rolls back.
+pub fn start_local_session(
+    commands: &mut Commands,
+    num_players: usize,
+    check_distance: usize,
+) {
+    let mut session_builder = SessionBuilder::<GGRSConfig>::new()
+        .with_num_players(num_players)
+        .with_max_prediction_window(MAX_PREDICTION_FRAMES)
+        .with_check_distance(check_distance);
+
+    for handle in 0..num_players {
+        session_builder = session_builder
+            .add_player(PlayerType::Local, handle)
+            .expect("Invalid player added.");
+    }
+
+    let session = session_builder
+        .start_synctest_session()
+        .expect("Invalid sync test session");
+
+    commands.insert_resource(session);
+    commands.insert_resource(SessionType::SyncTestSession);
+    commands.insert_resource(ManualStepping);
+}
+
+/// Starts every match from a clean rollback state. Resets in place rather
+/// than through commands, the match systems run right after this.
+pub fn reset_rollback_state(
+    mut frame_count: ResMut<FrameCount>,
+    mut checksum: ResMut<StateChecksum>,
+    mut history: ResMut<ChecksumHistory>,
+    mut outcome: ResMut<MatchOutcome>,
+    mut physics: ResMut<PhysicsSnapshot>,
+) {
+    *frame_count = FrameCount::default();
+    *checksum = StateChecksum::default();
+    *history = ChecksumHistory::default();
+    *outcome = MatchOutcome::default();
+    *physics = PhysicsSnapshot::default();
+}
+
+/// Stops the rollback simulation by dropping the running session.
+pub fn end_session(mut commands: Commands) {
+    commands.insert_resource(ConnectionStatus::default());
+    commands.insert_resource(LateSpectators::default());
+    commands.remove_resource::<SessionType>();
+    commands.remove_resource::<ManualStepping>();
+    commands.remove_resource::<P2PSession<GGRSConfig>>();
+    commands.remove_resource::<SpectatorSession<GGRSConfig>>();
+    commands.remove_resource::<SyncTestSession<GGRSConfig>>();
+}
+
+/// Frame count up to which all frames were simulated with confirmed inputs
+/// only, they are never rolled back. Outside of P2P sessions every input is
+/// confirmed.
+pub fn confirmed_frames(
+    session: Option<&P2PSession<GGRSConfig>>,
+    frame_count: &FrameCount,
+) -> u32 {
+    match session {
+        // GGRS counts frames from 0, -1 if none is confirmed yet
+        Some(session) => {
+            let confirmed = (session.confirmed_frame() + 1).max(0) as u32;
+            confirmed.min(frame_count.frame)
+        }
+        None => frame_count.frame,
+    }
 }
 
 pub fn increase_frame_system(mut frame_count: ResMut<FrameCount>) {
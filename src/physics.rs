@@ -0,0 +1,211 @@
+use bevy::{ecs::schedule::ShouldRun, prelude::*};
+use bevy_rapier2d::prelude::*;
+
+use crate::{
+    debug_ui::Logger,
+    game::{GameStage, FRAME_DURATION},
+    net::FrameCount,
+};
+
+pub const ROLLBACK_PHYSICS_RESTORE: &str = "rollback_physics_restore";
+pub const ROLLBACK_PHYSICS_SNAPSHOT: &str = "rollback_physics_snapshot";
+
+/// Present while the physics are stepped by the rollback schedule instead of
+/// the app schedule: during a session, in the replay viewer and in headless
+/// runs.
+pub struct ManualStepping;
+
+/// The rapier world at the end of a frame, serialized. Rolled back, so
+/// resimulated frames step the exact physics state they first stepped.
+/// Empty before the first physics step of a match.
+#[derive(Default, Reflect, Component)]
+pub struct PhysicsSnapshot {
+    /// Frame count the snapshot was taken at
+    frame: u32,
+    bytes: Vec<u8>,
+}
+
+/// Frame count the rapier world was last snapshotted at. Not rolled back:
+/// when the snapshot is of another frame, GGRS loaded an older state.
+#[derive(Default)]
+struct SnapshotFrame(Option<u32>);
+
+/// Rapier with its systems split up: during a match or replay they run inside
+/// the rollback schedule at the GGRS frame rate, outside of one (the lobby)
+/// in the regular app schedule.
+pub struct PhysicsPlugin;
+
+impl Plugin for PhysicsPlugin {
+    fn build(&self, app: &mut App) {
+        app.add_plugin(
+            RapierPhysicsPlugin::<NoUserData>::pixels_per_meter(1.0)
+                .with_default_system_setup(false),
+        )
+        .insert_resource(PhysicsSnapshot::default())
+        .insert_resource(SnapshotFrame::default())
+        .add_startup_system(configure_physics)
+        .add_stage_after(
+            CoreStage::Update,
+            PhysicsStages::SyncBackend,
+            free_running_stage(PhysicsStages::SyncBackend),
+        )
+        .add_stage_after(
+            PhysicsStages::SyncBackend,
+            PhysicsStages::StepSimulation,
+            free_running_stage(PhysicsStages::StepSimulation),
+        )
+        .add_stage_after(
+            PhysicsStages::StepSimulation,
+            PhysicsStages::Writeback,
+            free_running_stage(PhysicsStages::Writeback),
+        )
+        // Despawns happen outside of the rollback schedule too
+        .add_stage_before(
+            CoreStage::Last,
+            PhysicsStages::DetectDespawn,
+            SystemStage::parallel().with_system_set(
+                RapierPhysicsPlugin::<NoUserData>::get_systems(
+                    PhysicsStages::DetectDespawn,
+                ),
+            ),
+        );
+    }
+}
+
+/// One rapier stage for the rollback schedule.
+pub fn rollback_stage(stage: PhysicsStages) -> SystemStage {
+    SystemStage::parallel()
+        .with_system_set(RapierPhysicsPlugin::<NoUserData>::get_systems(stage))
+}
+
+/// One rapier stage for the app schedule, paused while `ManualStepping`.
+fn free_running_stage(stage: PhysicsStages) -> SystemStage {
+    SystemStage::parallel().with_system_set(
+        RapierPhysicsPlugin::<NoUserData>::get_systems(stage)
+            .with_run_criteria(not_rolled_back),
+    )
+}
+
+fn not_rolled_back(manual: Option<Res<ManualStepping>>) -> ShouldRun {
+    match manual {
+        Some(_) => ShouldRun::No,
+        None => ShouldRun::Yes,
+    }
+}
+
+/// One physics step per frame, independent of the render frame rate.
+fn configure_physics(mut config: ResMut<RapierConfiguration>) {
+    config.timestep_mode = TimestepMode::Fixed {
+        dt: FRAME_DURATION.as_secs_f32(),
+        substeps: 1,
+    };
+}
+
+/// Runs after the frame count was increased, the snapshot is of the end of
+/// the frame.
+pub fn save_physics_snapshot(
+    frame_count: Res<FrameCount>,
+    rapier_context: Res<RapierContext>,
+    mut snapshot: ResMut<PhysicsSnapshot>,
+    mut snapshot_frame: ResMut<SnapshotFrame>,
+) {
+    match bincode::serialize(rapier_context.as_ref()) {
+        Ok(bytes) => {
+            snapshot.frame = frame_count.frame;
+            snapshot.bytes = bytes;
+            snapshot_frame.0 = Some(frame_count.frame);
+        }
+        Err(e) => warn!("Failed to snapshot physics: {}", e),
+    }
+}
+
+/// Puts the rapier world back to the frame GGRS rolled back to. Without a
+/// rollback the world is still at the end of the previous frame and left
+/// alone.
+pub fn restore_physics_snapshot(
+    mut commands: Commands,
+    snapshot: Res<PhysicsSnapshot>,
+    mut snapshot_frame: ResMut<SnapshotFrame>,
+    mut rapier_context: ResMut<RapierContext>,
+    bodies: Query<(Entity, &RapierRigidBodyHandle)>,
+    colliders: Query<(Entity, &RapierColliderHandle)>,
+    mut state: ResMut<State<GameStage>>,
+    mut logger: ResMut<Logger>,
+) {
+    // Before the first step of the match, rebuild the world from scratch.
+    // Bodies are then created in the same order as in the first simulation.
+    if snapshot.bytes.is_empty() {
+        *rapier_context = RapierContext::default();
+        snapshot_frame.0 = None;
+        for (entity, _) in bodies.iter() {
+            commands.entity(entity).remove::<RapierRigidBodyHandle>();
+        }
+        for (entity, _) in colliders.iter() {
+            commands.entity(entity).remove::<RapierColliderHandle>();
+        }
+        return;
+    }
+
+    if snapshot_frame.0 == Some(snapshot.frame) {
+        return;
+    }
+
+    let saved: RapierContext = match bincode::deserialize(&snapshot.bytes) {
+        Ok(saved) => saved,
+        Err(e) => {
+            warn!("Failed to restore physics: {}", e);
+            return;
+        }
+    };
+
+    // The entity to handle maps aren't serialized, the ones we have only
+    // still match if no body was created or removed since the first frame.
+    // Restoring anyway would move bodies onto the wrong entities.
+    if !handles_match(&saved, &bodies, &colliders) {
+        error!("Physics bodies changed during the match, can't roll back");
+        // The replay viewer keeps its current physics instead
+        let in_match = *state.current() == GameStage::Gameplay;
+        if in_match && state.set(GameStage::Lobby).is_ok() {
+            logger.warn(
+                "Physics bodies changed, match aborted, returning to lobby."
+                    .to_string(),
+            );
+        }
+        return;
+    }
+
+    snapshot_frame.0 = Some(snapshot.frame);
+    rapier_context.bodies = saved.bodies;
+    rapier_context.colliders = saved.colliders;
+    rapier_context.impulse_joints = saved.impulse_joints;
+    rapier_context.multibody_joints = saved.multibody_joints;
+    rapier_context.islands = saved.islands;
+    rapier_context.broad_phase = saved.broad_phase;
+    rapier_context.narrow_phase = saved.narrow_phase;
+    rapier_context.ccd_solver = saved.ccd_solver;
+    rapier_context.query_pipeline = saved.query_pipeline;
+}
+
+/// Whether the handles of our entities point at the same entities in `saved`.
+/// Rapier bodies and colliders keep the bits of their entity as user data.
+fn handles_match(
+    saved: &RapierContext,
+    bodies: &Query<(Entity, &RapierRigidBodyHandle)>,
+    colliders: &Query<(Entity, &RapierColliderHandle)>,
+) -> bool {
+    let entity_bits = |entity: Entity| entity.to_bits() as u128;
+
+    saved.bodies.len() == bodies.iter().count()
+        && saved.colliders.len() == colliders.iter().count()
+        && bodies.iter().all(|(entity, handle)| {
+            saved.bodies.get(handle.0).map(|body| body.user_data)
+                == Some(entity_bits(entity))
+        })
+        && colliders.iter().all(|(entity, handle)| {
+            saved
+                .colliders
+                .get(handle.0)
+                .map(|collider| collider.user_data)
+                == Some(entity_bits(entity))
+        })
+}
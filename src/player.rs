@@ -1,8 +1,9 @@
 use bevy::{
+    ecs::system::EntityCommands,
     prelude::{
-        info, AssetServer, Assets, BuildChildren, Commands, Component, Deref,
-        DerefMut, Entity, Handle, Image, In, Input, KeyCode, Plugin, Query,
-        Res, ResMut, Transform, Vec2, Vec3, With,
+        info, AssetServer, Assets, BuildChildren, Color, Commands, Component,
+        Deref, DerefMut, Entity, Handle, Image, In, Plugin, Query, Res, ResMut,
+        Transform, Vec2, Vec3, Vec4, Visibility, With,
     },
     reflect::Reflect,
     sprite::{
@@ -14,67 +15,150 @@ use bevy::{
 use bevy_ggrs::{Rollback, RollbackIdProvider};
 use bevy_inspector_egui::Inspectable;
 use bevy_rapier2d::prelude::{
-    Collider, CollisionGroups, Friction, GravityScale, LockedAxes, RigidBody,
-    SolverGroups, Velocity,
+    Collider, CollisionGroups, Friction, GravityScale, LockedAxes, QueryFilter,
+    RapierContext, RigidBody, SolverGroups, Velocity,
 };
-use ggrs::{InputStatus, P2PSession, PlayerHandle, PlayerType};
+use ggrs::{InputStatus, PlayerHandle, PlayerType};
 
 use crate::{
+    combat::apply_melee,
+    config::GameConfig,
     debug_ui::Logger,
-    game::{spawn_fireball, Fireball, FireballTimer, GameStage, GameState},
-    net::{BoxInput, GGRSConfig},
+    game::{spawn_fireball, MatchMode},
+    headless::InputScript,
+    hero::{
+        Ability, Hero, HeroChoice, HeroDefinition, HeroDefinitions, Hurtbox,
+        SelectedHero,
+    },
+    input::{Action, ActionState, InputDevices},
+    lobby::{LocalPlayers, MatchRoster},
+    net::{BoxInput, FrameCount},
 };
 // use crate::net::{BoxInput, GGRSConfig};
 
-const INPUT_UP: u8 = 1 << 0;
-const INPUT_DOWN: u8 = 1 << 1;
-const INPUT_LEFT: u8 = 1 << 2;
-const INPUT_RIGHT: u8 = 1 << 3;
-const INPUT_SPACE: u8 = 1 << 4;
-
-const PLAYER_SPEED: f32 = 400.;
+// `BoxInput::buttons` bits, changing them needs a new `net::INPUT_VERSION`
+pub const INPUT_UP: u16 = 1 << 0;
+pub const INPUT_DOWN: u16 = 1 << 1;
+pub const INPUT_LEFT: u16 = 1 << 2;
+pub const INPUT_RIGHT: u16 = 1 << 3;
+pub const INPUT_ATTACK: u16 = 1 << 4;
+pub const INPUT_SPECIAL: u16 = 1 << 5;
+pub const INPUT_SHIELD: u16 = 1 << 6;
+
+// Sprites and hurtboxes of heroes are drawn at twice their size
+const PLAYER_SCALE: f32 = 2.0;
+// Frames after walking off a ledge in which a ground jump still works
+const COYOTE_FRAMES: u32 = 6;
+// Ground this far below the hurtbox still counts as standing on it
+const GROUND_TOLERANCE: f32 = 4.0;
+// Vertical speed below which a player touching the ground is grounded,
+// contacts leave some jitter on players standing still
+const GROUNDED_MAX_RISE: f32 = 1.0;
 
 const PLAYER_COLLISION_GROUP: u32 = 0b01;
 const OTHER_COLLISION_GROUP: u32 = 0b10;
 
+// Indexed by player handle, sized for `config::MAX_PLAYERS`
+pub const SPAWN_POINTS: [(f32, f32); 4] =
+    [(-100.0, 80.0), (100.0, 80.0), (-300.0, 80.0), (300.0, 80.0)];
+const PLAYER_COLORS: [Color; 4] = [
+    Color::WHITE,
+    Color::rgb(1.0, 0.5, 0.5),
+    Color::rgb(0.5, 0.7, 1.0),
+    Color::rgb(1.0, 0.9, 0.4),
+];
+
 #[derive(Component, Deref, DerefMut)]
 pub struct AnimationTimer(Timer);
 
-#[derive(Component, Reflect, Inspectable, Default)]
+#[derive(Component, Reflect, Inspectable, Default, Debug)]
 pub struct Player {
     pub handle: usize,
+    /// Rollback state, the sprite's `flip_x` just mirrors it
+    pub facing_left: bool,
+    /// Accumulated damage in percent, knockback grows with it
+    pub damage: f32,
+    /// Frames left in which the player can't act after being hit
+    pub hitstun: u32,
+    /// Lives left, at zero the player is out of the match
+    pub stocks: u32,
+    /// Frames left in which the player can't be hit after respawning
+    pub invulnerable: u32,
 }
 
-pub fn setup_lobby_player(
-    mut commands: Commands,
-    asset_server: Res<AssetServer>,
-    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
-    mut game_state: ResMut<GameState>,
-    mut logger: ResMut<Logger>,
-) {
-    // We are not in the stage to setup a lobby player, go on...
-    if game_state.stage != GameStage::SetupLobbyPlayer {
-        return;
-    }
+/// Ground contact and jumps of a player, rolled back with it
+#[derive(Component, Reflect, Default, Debug)]
+pub struct JumpState {
+    pub grounded: bool,
+    /// Jumps left until the player touches the ground again
+    pub air_jumps: u32,
+    /// Frames left in which a ground jump still works after leaving the
+    /// ground
+    pub coyote_frames: u32,
+    /// Whether jump was held in the previous frame
+    pub jump_held: bool,
+}
+
+/// Frames until the hero's abilities can be used again
+#[derive(Component, Reflect, Default, Debug)]
+pub struct AbilityCooldowns {
+    pub primary: u32,
+    pub special: u32,
+}
 
-    let texture_handle = asset_server.load("venomancer_idle.png");
-    let texture_atlas =
-        TextureAtlas::from_grid(texture_handle, Vec2::new(100.0, 100.0), 5, 1);
+/// Spawns a player playing the chosen hero, the caller adds `Rollback` if
+/// needed.
+fn spawn_player<'w, 's, 'a>(
+    commands: &'a mut Commands<'w, 's>,
+    asset_server: &AssetServer,
+    texture_atlases: &mut Assets<TextureAtlas>,
+    (choice, def): (HeroChoice, &HeroDefinition),
+    handle: usize,
+    stocks: u32,
+) -> EntityCommands<'w, 's, 'a> {
+    let texture_handle = asset_server.load(def.idle.path.as_str());
+    let texture_atlas = TextureAtlas::from_grid(
+        texture_handle,
+        def.idle.tile_size,
+        def.idle.columns,
+        def.idle.rows,
+    );
     let texture_atlas_handle = texture_atlases.add(texture_atlas);
 
-    let transform = Transform::from_xyz(-100.0, 80.0, 1.0);
+    let mut color = PLAYER_COLORS[handle];
+    if let Some(skin) = def.skin(choice.skin) {
+        color = color * Vec4::from(skin.tint);
+    }
+
+    let (x, y) = SPAWN_POINTS[handle];
+    let transform = Transform::from_xyz(x, y, 1.0);
+
+    let mut entity = commands.spawn();
 
-    commands
-        .spawn()
-        .insert(Player { handle: 0 })
+    entity
+        .insert(Player {
+            handle,
+            stocks,
+            ..Default::default()
+        })
+        .insert(choice.hero)
+        .insert(AbilityCooldowns::default())
+        .insert(JumpState::default())
         .insert_bundle(SpriteSheetBundle {
             texture_atlas: texture_atlas_handle,
+            sprite: TextureAtlasSprite {
+                color,
+                ..Default::default()
+            },
             ..Default::default()
         })
-        .insert(AnimationTimer(Timer::from_seconds(0.15, true)))
+        .insert(AnimationTimer(Timer::from_seconds(
+            def.idle.frame_seconds,
+            true,
+        )))
         .insert_bundle(TransformBundle::from(transform.with_scale(Vec3 {
-            x: 2.0,
-            y: 2.0,
+            x: PLAYER_SCALE,
+            y: PLAYER_SCALE,
             z: 1.0,
         })))
         .insert(RigidBody::Dynamic)
@@ -83,76 +167,122 @@ pub fn setup_lobby_player(
             ..Default::default()
         })
         .insert(Velocity::default())
-        .insert(GravityScale(10.0))
+        .insert(GravityScale(def.movement.gravity_scale))
         .insert(LockedAxes::ROTATION_LOCKED)
         .with_children(|children| {
             children
                 .spawn()
-                .insert(Collider::cuboid(25.0, 30.0))
+                .insert(Collider::cuboid(
+                    def.hurtbox.half_extents.x,
+                    def.hurtbox.half_extents.y,
+                ))
                 .insert(CollisionGroups::new(
                     PLAYER_COLLISION_GROUP,
                     OTHER_COLLISION_GROUP,
                 ))
                 .insert_bundle(TransformBundle::from(Transform::from_xyz(
-                    0.0, -40.0, 0.0,
+                    def.hurtbox.offset.x,
+                    def.hurtbox.offset.y,
+                    0.0,
                 )));
         });
 
-    logger.info("Local Player initialized!".to_string());
+    entity
+}
+
+pub fn setup_lobby_player(
+    mut commands: Commands,
+    asset_server: Res<AssetServer>,
+    mut texture_atlases: ResMut<Assets<TextureAtlas>>,
+    selected_hero: Res<SelectedHero>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    config: Res<GameConfig>,
+    mode: Res<MatchMode>,
+    mut logger: ResMut<Logger>,
+) {
+    // Spectators don't play
+    if *mode == MatchMode::Spectate {
+        return;
+    }
+
+    let choice = selected_hero.0;
+    let def = definitions
+        .get(choice.hero, &hero_assets)
+        .expect("Hero definitions are loaded before the lobby");
 
-    game_state.stage = GameStage::SetupSocket;
+    spawn_player(
+        &mut commands,
+        &asset_server,
+        &mut texture_atlases,
+        (choice, def),
+        0,
+        config.stocks,
+    );
+
+    logger.info("Local Player initialized!".to_string());
 }
 
 pub fn local_input_system(
     mut commands: Commands,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     asset_server: Res<AssetServer>,
-    keyboard_input: Res<Input<KeyCode>>,
-    game_state: Res<GameState>,
+    devices: InputDevices,
     mut query: Query<(
-        &Player,
+        &mut Player,
+        &Hero,
         &Transform,
-        &mut TextureAtlasSprite,
         &mut Velocity,
+        &mut AbilityCooldowns,
     )>,
-    fireball_query: Query<(&Fireball, &FireballTimer)>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
     mut rip: ResMut<RollbackIdProvider>,
 ) {
-    // This system should only work while we are waiting for new players to join
-    if game_state.stage == GameStage::Gameplay {
-        return;
-    }
-
-    for (p, t, mut s, mut v) in query.iter_mut() {
+    let actions = devices.primary_actions();
+
+    for (mut p, hero, t, mut v, mut cooldowns) in query.iter_mut() {
+        let def = match definitions.get(*hero, &hero_assets) {
+            Some(def) => def,
+            None => continue,
+        };
+        // Counted in updates, the lobby isn't stepped in frames
+        cooldowns.primary = cooldowns.primary.saturating_sub(1);
+        let speed = def.movement.speed;
         let mut no_move_key_pressed = true;
 
-        if keyboard_input.pressed(KeyCode::W) {
-            v.linvel.y = PLAYER_SPEED;
+        if actions.pressed(Action::Jump) {
+            v.linvel.y = speed;
             no_move_key_pressed = false;
         }
-        if keyboard_input.pressed(KeyCode::A) {
-            s.flip_x = true;
-            v.linvel.x = -PLAYER_SPEED;
+        if actions.pressed(Action::MoveLeft) {
+            p.facing_left = true;
+            v.linvel.x = -speed;
             no_move_key_pressed = false;
         }
-        if keyboard_input.pressed(KeyCode::S) {
-            v.linvel.y = -PLAYER_SPEED;
+        if actions.pressed(Action::MoveDown) {
+            v.linvel.y = -speed;
             no_move_key_pressed = false;
         }
-        if keyboard_input.pressed(KeyCode::D) {
-            s.flip_x = false;
-            v.linvel.x = PLAYER_SPEED;
+        if actions.pressed(Action::MoveRight) {
+            p.facing_left = false;
+            v.linvel.x = speed;
             no_move_key_pressed = false;
         }
-        if keyboard_input.pressed(KeyCode::Space) {
-            spawn_fireball(
-                &mut commands,
-                &mut texture_atlases,
-                (p, t, &s),
-                &asset_server,
-                &mut rip,
-                &fireball_query,
-            );
+        // Melee abilities have nobody to hit in the lobby
+        if let Ability::Fireball(projectile) = &def.primary {
+            if actions.pressed(Action::Attack) && cooldowns.primary == 0 {
+                cooldowns.primary = projectile.cooldown_frames();
+                spawn_fireball(
+                    &mut commands,
+                    &mut texture_atlases,
+                    (&p, t),
+                    projectile,
+                    &def.hurtbox,
+                    &asset_server,
+                    &mut rip,
+                );
+            }
         }
         if no_move_key_pressed {
             v.linvel.x = 0.0
@@ -165,94 +295,31 @@ pub fn setup_gameplay_players(
     asset_server: Res<AssetServer>,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut rip: ResMut<RollbackIdProvider>,
-    session: Option<ResMut<P2PSession<GGRSConfig>>>,
-    mut game_state: ResMut<GameState>,
-    mut query: Query<(Entity, &Player, &RigidBody)>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    roster: Res<MatchRoster>,
+    config: Res<GameConfig>,
     mut logger: ResMut<Logger>,
 ) {
-    if game_state.stage != GameStage::SetupGameplayPlayers {
-        return;
-    }
-
-    // No session, skip
-    if session.is_none() {
-        return;
-    }
-
-    // Already setup
-    if query.iter().len() >= 2 {
-        return;
-    }
-
-    // Remove old players
-    for (e, p, r) in query.iter_mut() {
-        commands.entity(e).remove::<Collider>();
-        commands.entity(e).despawn();
-    }
-
-    let session = session.unwrap();
-    let num_players = session.num_players();
-
     logger.info("Sessions collected, initializing remote players.".to_string());
 
-    for handle in 0..num_players {
-        let texture_handle = asset_server.load("venomancer_idle.png");
-        let texture_atlas = TextureAtlas::from_grid(
-            texture_handle,
-            Vec2::new(100.0, 100.0),
-            5,
-            1,
-        );
-        let texture_atlas_handle = texture_atlases.add(texture_atlas);
-
-        let mut transform = Transform::default();
-
-        match handle {
-            0 => transform = Transform::from_xyz(-100.0, 80.0, 1.0),
-            1 => transform = Transform::from_xyz(100.0, 80.0, 1.0),
-            _ => (),
-        }
-
-        commands
-            .spawn()
-            .insert(Player { handle })
-            .insert_bundle(SpriteSheetBundle {
-                texture_atlas: texture_atlas_handle,
-                ..Default::default()
-            })
-            .insert(AnimationTimer(Timer::from_seconds(0.15, true)))
-            .insert_bundle(TransformBundle::from(transform.with_scale(Vec3 {
-                x: 2.0,
-                y: 2.0,
-                z: 1.0,
-            })))
-            .insert(RigidBody::Dynamic)
-            .insert(Friction {
-                coefficient: 0.0,
-                ..Default::default()
-            })
-            .insert(Velocity::default())
-            .insert(GravityScale(10.0))
-            .insert(LockedAxes::ROTATION_LOCKED)
-            .with_children(|children| {
-                children
-                    .spawn()
-                    .insert(Collider::cuboid(25.0, 30.0))
-                    .insert(CollisionGroups::new(
-                        PLAYER_COLLISION_GROUP,
-                        OTHER_COLLISION_GROUP,
-                    ))
-                    .insert_bundle(TransformBundle::from(Transform::from_xyz(
-                        0.0, -40.0, 0.0,
-                    )));
-            })
-            // .insert(CollisionGroups::new(0b0001, 0b0010))
-            .insert(Rollback::new(rip.next_id()));
+    for (handle, choice) in roster.heroes.iter().copied().enumerate() {
+        let def = definitions
+            .get(choice.hero, &hero_assets)
+            .expect("Hero definitions are loaded before the match");
+
+        spawn_player(
+            &mut commands,
+            &asset_server,
+            &mut texture_atlases,
+            (choice, def),
+            handle,
+            config.stocks,
+        )
+        .insert(Rollback::new(rip.next_id()));
     }
 
     logger.info("Remote Players initialized!".to_string());
-
-    game_state.stage = GameStage::Gameplay;
 }
 
 pub fn animate_players(
@@ -262,10 +329,19 @@ pub fn animate_players(
         &Player,
         &mut AnimationTimer,
         &mut TextureAtlasSprite,
+        &mut Visibility,
         &Handle<TextureAtlas>,
     )>,
 ) {
-    for (_, mut timer, mut sprite, texture_atlas_handle) in &mut query {
+    for (player, mut timer, mut sprite, mut visibility, texture_atlas_handle) in
+        &mut query
+    {
+        sprite.flip_x = player.facing_left;
+
+        // Hide eliminated players, blink while invulnerable
+        visibility.is_visible =
+            player.stocks > 0 && (player.invulnerable / 8) % 2 == 0;
+
         timer.tick(time.delta());
         if timer.just_finished() {
             let texture_atlas =
@@ -276,74 +352,180 @@ pub fn animate_players(
 }
 
 pub fn ggrs_input(
-    _handle: In<PlayerHandle>,
-    keyboard_input: Res<Input<KeyCode>>,
+    handle: In<PlayerHandle>,
+    devices: InputDevices,
+    mode: Res<MatchMode>,
+    local_players: Res<LocalPlayers>,
+    script: Option<Res<InputScript>>,
+    frame_count: Res<FrameCount>,
 ) -> BoxInput {
-    let mut input: u8 = 0;
-
-    if keyboard_input.pressed(KeyCode::W) {
-        input |= INPUT_UP;
-    }
-    if keyboard_input.pressed(KeyCode::A) {
-        input |= INPUT_LEFT;
-    }
-    if keyboard_input.pressed(KeyCode::S) {
-        input |= INPUT_DOWN;
-    }
-    if keyboard_input.pressed(KeyCode::D) {
-        input |= INPUT_RIGHT;
-    }
-    if keyboard_input.pressed(KeyCode::Space) {
-        input |= INPUT_SPACE;
+    // Headless apps play online from their script
+    if let Some(script) = script {
+        return script.input(handle.0, frame_count.frame);
     }
 
-    BoxInput { inp: input }
+    let actions = match *mode {
+        // The training dummy just stands there
+        MatchMode::Training if handle.0 != 0 => ActionState::default(),
+        // Every player of a local versus on their own controls
+        MatchMode::LocalVersus => match local_players.players.get(handle.0) {
+            Some(player) => devices.actions(player.controls),
+            None => ActionState::default(),
+        },
+        _ => devices.primary_actions(),
+    };
+
+    actions.to_input()
+}
+
+/// Whether the static stage is right below the player's hurtbox. Only the
+/// stage is queried, it never moves, so this is safe to use in rollbacks.
+fn is_grounded(
+    rapier_context: &RapierContext,
+    transform: &Transform,
+    hurtbox: &Hurtbox,
+) -> bool {
+    let center = hurtbox_center(transform, hurtbox);
+    let max_toi = hurtbox.half_extents.y * PLAYER_SCALE + GROUND_TOLERANCE;
+
+    rapier_context
+        .cast_ray(center, -Vec2::Y, max_toi, true, QueryFilter::only_fixed())
+        .is_some()
+}
+
+/// Where the hurtbox of a player at `transform` is centred in the world.
+pub fn hurtbox_center(transform: &Transform, hurtbox: &Hurtbox) -> Vec2 {
+    transform.translation.truncate() + hurtbox.offset * PLAYER_SCALE
+}
+
+/// Full size of the hurtbox in the world.
+pub fn hurtbox_size(hurtbox: &Hurtbox) -> Vec2 {
+    2.0 * hurtbox.half_extents * PLAYER_SCALE
 }
 
 pub fn ggrs_move_player_system(
     mut commands: Commands,
     mut texture_atlases: ResMut<Assets<TextureAtlas>>,
     mut query: Query<
-        (&Player, &Transform, &mut Velocity, &mut TextureAtlasSprite),
+        (
+            &mut Player,
+            &Hero,
+            &Transform,
+            &mut Velocity,
+            &mut AbilityCooldowns,
+            &mut JumpState,
+        ),
         With<Rollback>,
     >,
-    fireball_query: Query<(&Fireball, &FireballTimer)>,
     asset_server: Res<AssetServer>,
-    game_state: Res<GameState>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+    rapier_context: Res<RapierContext>,
     inputs: Res<Vec<(BoxInput, InputStatus)>>,
     mut rip: ResMut<RollbackIdProvider>,
 ) {
-    if game_state.stage != GameStage::Gameplay {
-        return;
-    }
+    // Melee abilities hit other players, resolved once everyone moved
+    let mut melee_casts: Vec<(usize, &Ability, Vec3)> = Vec::new();
+
+    for (mut p, hero, t, mut v, mut cooldowns, mut jump) in query.iter_mut() {
+        let def = match definitions.get(*hero, &hero_assets) {
+            Some(def) => def,
+            None => continue,
+        };
+        let movement = &def.movement;
+
+        cooldowns.primary = cooldowns.primary.saturating_sub(1);
+        cooldowns.special = cooldowns.special.saturating_sub(1);
+
+        // Rising players just jumped off the ground, they aren't on it
+        jump.grounded = v.linvel.y <= GROUNDED_MAX_RISE
+            && is_grounded(&rapier_context, t, &def.hurtbox);
+
+        if jump.grounded {
+            jump.coyote_frames = COYOTE_FRAMES;
+            jump.air_jumps = movement.air_jumps;
+        } else {
+            jump.coyote_frames = jump.coyote_frames.saturating_sub(1);
+        }
+
+        let input = inputs[p.handle as usize].0.buttons();
 
-    for (p, t, mut v, mut s) in query.iter_mut() {
-        let input = inputs[p.handle as usize].0.inp;
+        // Jumps need a fresh press, holding jump doesn't chain them
+        let jump_pressed = input & INPUT_UP != 0 && !jump.jump_held;
+        jump.jump_held = input & INPUT_UP != 0;
+
+        // Knocked back players drift with the knockback, ignoring input
+        if p.hitstun > 0 {
+            p.hitstun -= 1;
+            continue;
+        }
 
         if input & INPUT_LEFT != 0 {
-            s.flip_x = true;
-            v.linvel.x = -PLAYER_SPEED;
+            p.facing_left = true;
+            v.linvel.x = -movement.speed;
         }
         if input & INPUT_RIGHT != 0 {
-            s.flip_x = false;
-            v.linvel.x = PLAYER_SPEED;
+            p.facing_left = false;
+            v.linvel.x = movement.speed;
         }
-        if input & INPUT_UP != 0 {
-            v.linvel.y = PLAYER_SPEED;
+        if input & (INPUT_LEFT | INPUT_RIGHT) == 0 {
+            v.linvel.x = 0.;
         }
-        if input & INPUT_SPACE != 0 {
-            spawn_fireball(
-                &mut commands,
-                &mut texture_atlases,
-                (p, t, &s),
-                &asset_server,
-                &mut rip,
-                &fireball_query,
-            );
+
+        if jump_pressed {
+            if jump.coyote_frames > 0 {
+                jump.coyote_frames = 0;
+                jump.grounded = false;
+                v.linvel.y = movement.jump_speed;
+            } else if jump.air_jumps > 0 {
+                jump.air_jumps -= 1;
+                v.linvel.y = movement.jump_speed;
+            }
         }
 
-        if input == 0 {
-            v.linvel.x = 0.;
+        if input & INPUT_DOWN != 0 && !jump.grounded {
+            v.linvel.y = v.linvel.y.min(-movement.fast_fall_speed);
+        }
+
+        let mut casts = Vec::new();
+        if input & INPUT_ATTACK != 0 && cooldowns.primary == 0 {
+            cooldowns.primary = def.primary.cooldown_frames();
+            casts.push(&def.primary);
+        }
+        if let Some(special) = &def.special {
+            if input & INPUT_SPECIAL != 0 && cooldowns.special == 0 {
+                cooldowns.special = special.cooldown_frames();
+                casts.push(special);
+            }
+        }
+
+        for ability in casts {
+            match ability {
+                Ability::Fireball(projectile) => spawn_fireball(
+                    &mut commands,
+                    &mut texture_atlases,
+                    (&p, t),
+                    projectile,
+                    &def.hurtbox,
+                    &asset_server,
+                    &mut rip,
+                ),
+                _ => melee_casts.push((p.handle, ability, t.translation)),
+            }
+        }
+    }
+
+    // Hits on the same player add up damage before the knockback of the
+    // next, so they land in the same order on every peer whatever order the
+    // query returned the casters in
+    melee_casts.sort_by_key(|(caster, ..)| *caster);
+    for (caster, ability, origin) in melee_casts {
+        for (mut p, _, t, mut v, ..) in query.iter_mut() {
+            if p.handle == caster || p.invulnerable > 0 || p.stocks == 0 {
+                continue;
+            }
+
+            apply_melee(ability, origin, &mut p, t, &mut v);
         }
     }
 }
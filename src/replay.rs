@@ -0,0 +1,406 @@
+use bevy::prelude::{Assets, Mut, Res, ResMut, State, Time, World};
+use ggrs::InputStatus;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    config::GameConfig,
+    game::{BlastZone, GameStage, FPS, FRAME_DURATION},
+    hero::{HeroChoice, HeroDefinition, HeroDefinitions},
+    lobby::MatchRoster,
+    net::{self, BoxInput, FrameCount, INPUT_VERSION},
+};
+
+const REPLAY_MAGIC: &[u8; 4] = b"DSRP";
+// Bumped whenever the layout of `Replay` changes
+const REPLAY_FORMAT: u8 = 2;
+
+#[cfg(not(target_arch = "wasm32"))]
+const REPLAY_DIR: &str = "replays";
+#[cfg(not(target_arch = "wasm32"))]
+const REPLAY_EXTENSION: &str = "replay";
+
+// Seeking simulates the skipped frames, this many per update at most so the
+// viewer stays responsive
+const MAX_SEEK_FRAMES_PER_UPDATE: u32 = 600;
+// Playback never catches up on more than this many frames per update
+const MAX_PLAYBACK_FRAMES_PER_UPDATE: u32 = 8;
+
+pub const MIN_PLAYBACK_SPEED: f32 = 0.25;
+pub const MAX_PLAYBACK_SPEED: f32 = 4.0;
+
+/// Consecutive frames with the same input for every player.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+struct InputRun {
+    frames: u32,
+    inputs: Vec<BoxInput>,
+}
+
+/// A recorded match: the rules it was played with, the heroes and the input
+/// of every player handle for every frame. Simulating the inputs again gives
+/// the exact same match, as long as the hero definitions didn't change.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Replay {
+    /// `INPUT_VERSION` the match was played with
+    pub input_version: u8,
+    /// Rollback frames per second
+    pub fps: u32,
+    pub stocks: u32,
+    pub blast_zone: BlastZone,
+    /// Indexed by player handle
+    pub heroes: Vec<HeroChoice>,
+    /// `HeroDefinitions::hash` of the definitions the match was played with
+    pub heroes_hash: u64,
+    runs: Vec<InputRun>,
+}
+
+impl Replay {
+    pub fn num_frames(&self) -> u32 {
+        self.runs.iter().map(|run| run.frames).sum()
+    }
+
+    /// Whether this build simulates the replay the way it was recorded, with
+    /// hero definitions of the given hash.
+    pub fn compatible(&self, heroes_hash: u64) -> bool {
+        self.input_version == INPUT_VERSION
+            && self.fps == FPS as u32
+            && self.heroes_hash == heroes_hash
+    }
+
+    pub fn encode(&self) -> Result<Vec<u8>, String> {
+        let body = bincode::serialize(self).map_err(|e| e.to_string())?;
+
+        let mut bytes = REPLAY_MAGIC.to_vec();
+        bytes.push(REPLAY_FORMAT);
+        bytes.extend(body);
+        Ok(bytes)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Replay, String> {
+        let body = match bytes.strip_prefix(REPLAY_MAGIC) {
+            Some([REPLAY_FORMAT, body @ ..]) => body,
+            Some(_) => return Err("Unsupported replay format".to_string()),
+            None => return Err("Not a replay".to_string()),
+        };
+
+        bincode::deserialize(body).map_err(|e| e.to_string())
+    }
+
+    /// Inputs of every frame, the runs expanded.
+    pub fn frames(&self) -> Vec<Vec<BoxInput>> {
+        self.runs
+            .iter()
+            .flat_map(|run| (0..run.frames).map(|_| run.inputs.clone()))
+            .collect()
+    }
+}
+
+/// Collects the inputs of the running match. Not rolled back: resimulated
+/// frames overwrite what was recorded for them, so a frame ends up with the
+/// inputs it was last simulated with, the confirmed ones.
+#[derive(Default)]
+pub struct ReplayRecorder {
+    stocks: u32,
+    blast_zone: BlastZone,
+    heroes: Vec<HeroChoice>,
+    heroes_hash: u64,
+    frames: Vec<Vec<BoxInput>>,
+    /// File the recording was saved to, if it was
+    pub saved_as: Option<String>,
+}
+
+impl ReplayRecorder {
+    /// Drops the frames simulated after the frame count the match ended at.
+    pub fn finish(&mut self, frames: u32) {
+        self.frames.truncate(frames as usize);
+    }
+
+    pub fn replay(&self) -> Replay {
+        self.excerpt(0, self.num_frames())
+    }
+
+    pub fn num_frames(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    /// The frames from `start` up to `end` as a replay of their own, for
+    /// spectators that join the match while it runs.
+    pub fn excerpt(&self, start: u32, end: u32) -> Replay {
+        let end = (end as usize).min(self.frames.len());
+        let start = (start as usize).min(end);
+        let mut runs: Vec<InputRun> = Vec::new();
+
+        for inputs in self.frames[start..end].iter() {
+            match runs.last_mut() {
+                Some(run) if run.inputs == *inputs => run.frames += 1,
+                _ => runs.push(InputRun {
+                    frames: 1,
+                    inputs: inputs.clone(),
+                }),
+            }
+        }
+
+        Replay {
+            input_version: INPUT_VERSION,
+            fps: FPS as u32,
+            stocks: self.stocks,
+            blast_zone: self.blast_zone,
+            heroes: self.heroes.clone(),
+            heroes_hash: self.heroes_hash,
+            runs,
+        }
+    }
+}
+
+pub fn start_recording(
+    mut recorder: ResMut<ReplayRecorder>,
+    roster: Res<MatchRoster>,
+    config: Res<GameConfig>,
+    definitions: Res<HeroDefinitions>,
+    hero_assets: Res<Assets<HeroDefinition>>,
+) {
+    *recorder = ReplayRecorder {
+        stocks: config.stocks,
+        blast_zone: config.blast_zone,
+        heroes: roster.heroes.clone(),
+        heroes_hash: definitions.hash(&hero_assets),
+        ..Default::default()
+    };
+}
+
+/// Runs in the rollback schedule, before the frame count is increased.
+pub fn record_inputs(
+    frame_count: Res<FrameCount>,
+    inputs: Res<Vec<(BoxInput, InputStatus)>>,
+    mut recorder: ResMut<ReplayRecorder>,
+) {
+    let frame = frame_count.frame as usize;
+
+    recorder.frames.truncate(frame);
+    if recorder.frames.len() == frame {
+        recorder
+            .frames
+            .push(inputs.iter().map(|(input, _)| *input).collect());
+    }
+}
+
+/// Writes the replay to the replays directory, returns its file name.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn save(replay: &Replay) -> Result<String, String> {
+    let timestamp = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|time| time.as_secs())
+        .unwrap_or_default();
+    let name = format!("match_{}.{}", timestamp, REPLAY_EXTENSION);
+
+    let bytes = replay.encode()?;
+    std::fs::create_dir_all(REPLAY_DIR).map_err(|e| e.to_string())?;
+    std::fs::write(std::path::Path::new(REPLAY_DIR).join(&name), bytes)
+        .map_err(|e| e.to_string())?;
+
+    Ok(name)
+}
+
+/// Browsers have no replays directory.
+#[cfg(target_arch = "wasm32")]
+pub fn save(_replay: &Replay) -> Result<String, String> {
+    Err("Replays can't be saved in the browser".to_string())
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+pub fn load(name: &str) -> Result<Replay, String> {
+    let path = std::path::Path::new(REPLAY_DIR).join(name);
+    let bytes = std::fs::read(path).map_err(|e| e.to_string())?;
+    Replay::decode(&bytes)
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn load(name: &str) -> Result<Replay, String> {
+    Err(format!("Can't load {} in the browser", name))
+}
+
+/// File names of the saved replays, newest first.
+#[cfg(not(target_arch = "wasm32"))]
+pub fn saved_replays() -> Vec<String> {
+    let entries = match std::fs::read_dir(REPLAY_DIR) {
+        Ok(entries) => entries,
+        Err(_) => return Vec::new(),
+    };
+
+    let mut names: Vec<String> = entries
+        .filter_map(|entry| entry.ok())
+        .map(|entry| entry.file_name().to_string_lossy().to_string())
+        .filter(|name| name.ends_with(REPLAY_EXTENSION))
+        .collect();
+    names.sort_unstable_by(|a, b| b.cmp(a));
+    names
+}
+
+#[cfg(target_arch = "wasm32")]
+pub fn saved_replays() -> Vec<String> {
+    Vec::new()
+}
+
+/// The replay being watched. Only present in the replay viewer.
+pub struct ReplayPlayer {
+    pub replay: Replay,
+    frames: Vec<Vec<BoxInput>>,
+    /// Frames simulated so far
+    pub frame: u32,
+    pub paused: bool,
+    pub speed: f32,
+    /// Frame the viewer fast forwards to
+    seek: Option<u32>,
+    /// Playback time not yet simulated, in seconds
+    accumulator: f32,
+    /// Rules the replay replaced, restored when leaving the viewer
+    previous_config: GameConfig,
+}
+
+impl ReplayPlayer {
+    /// Plays the replay by its own rules until `stop` is called.
+    pub fn start(replay: Replay, config: &mut GameConfig) -> Self {
+        let previous_config = config.clone();
+        config.stocks = replay.stocks;
+        config.blast_zone = replay.blast_zone;
+
+        Self {
+            frames: replay.frames(),
+            replay,
+            frame: 0,
+            paused: false,
+            speed: 1.0,
+            seek: None,
+            accumulator: 0.0,
+            previous_config,
+        }
+    }
+
+    pub fn stop(&self, config: &mut GameConfig) {
+        *config = self.previous_config.clone();
+    }
+
+    pub fn num_frames(&self) -> u32 {
+        self.frames.len() as u32
+    }
+
+    pub fn seeking(&self) -> bool {
+        self.seek.is_some()
+    }
+
+    pub fn seek(&mut self, frame: u32) {
+        self.seek = Some(frame.min(self.num_frames()));
+    }
+
+    /// Advances a single frame, meant for while paused.
+    pub fn step(&mut self) {
+        self.seek(self.frame + 1);
+    }
+
+    fn next_inputs(&self) -> Option<&[BoxInput]> {
+        self.frames.get(self.frame as usize).map(Vec::as_slice)
+    }
+}
+
+/// Steps the rollback schedule by as many frames as playback speed, pause
+/// and seeking ask for.
+pub fn play_replay(world: &mut World) {
+    let delta = world.resource::<Time>().delta_seconds();
+
+    world.resource_scope(|world, mut player: Mut<ReplayPlayer>| {
+        // Frames can't be simulated backwards, start over and fast forward
+        if let Some(target) = player.seek {
+            if target < player.frame {
+                player.frame = 0;
+                world
+                    .resource_mut::<State<GameStage>>()
+                    .restart()
+                    .unwrap();
+                return;
+            }
+        }
+
+        let frames = match player.seek {
+            Some(target) => {
+                (target - player.frame).min(MAX_SEEK_FRAMES_PER_UPDATE)
+            }
+            None if player.paused => 0,
+            None => {
+                let frame_seconds = FRAME_DURATION.as_secs_f32();
+                player.accumulator += delta * player.speed;
+                let frames = (player.accumulator / frame_seconds) as u32;
+                player.accumulator -= frames as f32 * frame_seconds;
+                frames.min(MAX_PLAYBACK_FRAMES_PER_UPDATE)
+            }
+        };
+
+        for _ in 0..frames {
+            let inputs = match player.next_inputs() {
+                Some(inputs) => inputs,
+                None => break,
+            };
+
+            net::advance_frame(world, inputs);
+            player.frame += 1;
+        }
+
+        if player.seek == Some(player.frame) {
+            player.seek = None;
+        }
+        if player.frame >= player.num_frames() {
+            player.paused = true;
+            player.seek = None;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn replay() -> Replay {
+        let input = |buttons| BoxInput::new(buttons, Default::default());
+        let recorder = ReplayRecorder {
+            stocks: 2,
+            heroes: vec![HeroChoice::default(); 2],
+            heroes_hash: 42,
+            frames: vec![
+                vec![input(0), input(1)],
+                vec![input(0), input(1)],
+                vec![input(2), input(0)],
+            ],
+            ..Default::default()
+        };
+        recorder.replay()
+    }
+
+    #[test]
+    fn replays_round_trip() {
+        let replay = replay();
+        assert_eq!(replay.runs.len(), 2);
+
+        let decoded = Replay::decode(&replay.encode().unwrap()).unwrap();
+        assert_eq!(decoded.frames(), replay.frames());
+        assert_eq!(decoded.stocks, 2);
+        assert_eq!(decoded.heroes, replay.heroes);
+        assert!(decoded.compatible(42));
+        assert!(!decoded.compatible(43));
+    }
+
+    #[test]
+    fn other_files_are_rejected() {
+        let bytes = replay().encode().unwrap();
+
+        let mut bad_magic = bytes.clone();
+        bad_magic[0] = b'X';
+        assert_eq!(Replay::decode(&bad_magic).unwrap_err(), "Not a replay");
+
+        let mut bad_format = bytes.clone();
+        bad_format[REPLAY_MAGIC.len()] = REPLAY_FORMAT + 1;
+        assert_eq!(
+            Replay::decode(&bad_format).unwrap_err(),
+            "Unsupported replay format"
+        );
+
+        assert!(Replay::decode(&bytes[..bytes.len() - 1]).is_err());
+    }
+}
@@ -0,0 +1,162 @@
+use bevy::{input::mouse::MouseWheel, prelude::*};
+use bevy_egui::{egui::Window, EguiContext};
+
+use crate::{
+    game::{GameStage, MatchMode, CAMERA_SCALE},
+    player::Player,
+};
+
+// Room kept around the players when auto framing, in world units
+const FRAMING_MARGIN: f32 = 600.0;
+const MIN_CAMERA_SCALE: f32 = 1.0;
+const MAX_CAMERA_SCALE: f32 = 4.0;
+// How quickly the auto framing camera catches up, per second
+const FRAMING_SPEED: f32 = 4.0;
+// Free camera speeds, world units and scale per second at scale 1
+const PAN_SPEED: f32 = 800.0;
+const ZOOM_SPEED: f32 = 1.0;
+const WHEEL_ZOOM_STEP: f32 = 0.1;
+
+/// How the camera of a spectator moves.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub enum SpectatorCamera {
+    /// Follows the players, zoomed to keep all of them in view
+    #[default]
+    Auto,
+    /// Moved with WASD or the arrow keys, zoomed with Q/E or the mouse wheel
+    Free,
+}
+
+/// Read-only view of a match for spectators: no local input, a camera of
+/// their own and the regular HUD. Needs the `EguiPlugin`, which the
+/// `DebugUiPlugin` adds.
+pub struct SpectatorPlugin;
+
+impl Plugin for SpectatorPlugin {
+    fn build(&self, app: &mut App) {
+        app.insert_resource(SpectatorCamera::default())
+            .add_system_set(
+                SystemSet::on_update(GameStage::Gameplay)
+                    .with_system(move_spectator_camera)
+                    .with_system(spectator_window),
+            )
+            .add_system_set(
+                SystemSet::on_exit(GameStage::Gameplay)
+                    .with_system(reset_camera),
+            );
+    }
+}
+
+fn move_spectator_camera(
+    mode: Res<MatchMode>,
+    camera_mode: Res<SpectatorCamera>,
+    time: Res<Time>,
+    windows: Res<Windows>,
+    keys: Res<Input<KeyCode>>,
+    mut wheel: EventReader<MouseWheel>,
+    players: Query<(&Player, &Transform), Without<Camera2d>>,
+    mut camera: Query<
+        (&mut Transform, &mut OrthographicProjection),
+        With<Camera2d>,
+    >,
+) {
+    if *mode != MatchMode::Spectate {
+        return;
+    }
+
+    let (mut transform, mut projection) = match camera.get_single_mut() {
+        Ok(camera) => camera,
+        Err(_) => return,
+    };
+    let dt = time.delta_seconds();
+
+    match *camera_mode {
+        SpectatorCamera::Auto => {
+            let window = match windows.get_primary() {
+                Some(window) => window,
+                None => return,
+            };
+
+            // Eliminated players are parked far off stage
+            let positions: Vec<Vec2> = players
+                .iter()
+                .filter(|(p, _)| p.stocks > 0)
+                .map(|(_, t)| t.translation.truncate())
+                .collect();
+            if positions.is_empty() {
+                return;
+            }
+
+            let min = positions.iter().copied().reduce(Vec2::min).unwrap();
+            let max = positions.iter().copied().reduce(Vec2::max).unwrap();
+            let size = max - min + Vec2::splat(FRAMING_MARGIN * 2.0);
+
+            let target = (min + max) / 2.0;
+            let target_scale = (size.x / window.width())
+                .max(size.y / window.height())
+                .clamp(MIN_CAMERA_SCALE, MAX_CAMERA_SCALE);
+
+            // Frame rate independent easing
+            let t = 1.0 - (-FRAMING_SPEED * dt).exp();
+            let position = transform.translation.truncate().lerp(target, t);
+            transform.translation.x = position.x;
+            transform.translation.y = position.y;
+            projection.scale += (target_scale - projection.scale) * t;
+        }
+        SpectatorCamera::Free => {
+            let pressed = |a: KeyCode, b: KeyCode| {
+                (keys.pressed(a) || keys.pressed(b)) as i32 as f32
+            };
+            let direction = Vec2::new(
+                pressed(KeyCode::D, KeyCode::Right)
+                    - pressed(KeyCode::A, KeyCode::Left),
+                pressed(KeyCode::W, KeyCode::Up)
+                    - pressed(KeyCode::S, KeyCode::Down),
+            );
+            let pan = direction * PAN_SPEED * projection.scale * dt;
+            transform.translation.x += pan.x;
+            transform.translation.y += pan.y;
+
+            let mut zoom = (keys.pressed(KeyCode::E) as i32
+                - keys.pressed(KeyCode::Q) as i32)
+                as f32
+                * ZOOM_SPEED
+                * dt;
+            for event in wheel.iter() {
+                zoom -= event.y.signum() * WHEEL_ZOOM_STEP;
+            }
+            projection.scale = (projection.scale + zoom)
+                .clamp(MIN_CAMERA_SCALE, MAX_CAMERA_SCALE);
+        }
+    }
+}
+
+fn spectator_window(
+    mut egui_context: ResMut<EguiContext>,
+    mode: Res<MatchMode>,
+    mut camera_mode: ResMut<SpectatorCamera>,
+) {
+    if *mode != MatchMode::Spectate {
+        return;
+    }
+
+    Window::new("Spectating").show(egui_context.ctx_mut(), |ui| {
+        ui.radio_value(&mut *camera_mode, SpectatorCamera::Auto, "Auto camera");
+        ui.radio_value(&mut *camera_mode, SpectatorCamera::Free, "Free camera")
+            .on_hover_text("WASD/arrows to move, Q/E or mouse wheel to zoom");
+    });
+}
+
+/// Back to the fixed camera of the other stages.
+fn reset_camera(
+    mut camera: Query<
+        (&mut Transform, &mut OrthographicProjection),
+        With<Camera2d>,
+    >,
+) {
+    for (mut transform, mut projection) in camera.iter_mut() {
+        transform.translation.x = 0.0;
+        transform.translation.y = 0.0;
+        projection.scale = CAMERA_SCALE;
+    }
+}
@@ -0,0 +1,61 @@
+use dota_smash::{
+    harness::{BoxInput, InputScript, MatchHarness, INPUT_ATTACK, INPUT_LEFT},
+    GameConfig,
+};
+
+fn harness(script: InputScript) -> MatchHarness {
+    // Both handles play the default hero, a fireball caster
+    MatchHarness::new(GameConfig::default(), script)
+}
+
+#[test]
+fn fireball_hits_the_opponent() {
+    let script = InputScript::default().tap(0, 10, INPUT_ATTACK);
+    let mut harness = harness(script);
+
+    harness.advance_to(11);
+    assert_eq!(harness.fireballs().len(), 1);
+
+    harness.advance_to(40);
+    let (opponent, ..) = harness.player(1);
+    assert!(opponent.damage > 0.0, "damage: {}", opponent.damage);
+    assert!(harness.fireballs().is_empty());
+}
+
+#[test]
+fn fireball_respects_the_cooldown() {
+    // Facing away from the opponent, so the fireballs don't hit and vanish
+    let script = InputScript::default()
+        .tap(0, 5, INPUT_LEFT)
+        .hold(0, 10, BoxInput::new(INPUT_ATTACK, Default::default()));
+    let mut harness = harness(script);
+
+    // Held through the 0.2s cooldown
+    harness.advance_to(21);
+    assert_eq!(harness.fireballs().len(), 1);
+
+    harness.advance_to(40);
+    assert!(harness.fireballs().len() > 1);
+}
+
+#[test]
+fn fireball_cooldown_outlasts_a_hit() {
+    // Point-blank, the first fireball hits and vanishes well within the
+    // 0.2s cooldown
+    let script = InputScript::default()
+        .hold(0, 10, BoxInput::new(INPUT_ATTACK, Default::default()));
+    let mut harness = harness(script);
+
+    harness.advance_to(18);
+    let damage = harness.player(1).0.damage;
+    assert!(damage > 0.0);
+    assert!(harness.fireballs().is_empty());
+
+    // Still held, but the second cast waits for the cooldown
+    harness.advance_to(21);
+    assert_eq!(harness.player(1).0.damage, damage);
+    assert!(harness.fireballs().is_empty());
+
+    harness.advance_to(24);
+    assert_eq!(harness.fireballs().len(), 1);
+}
@@ -0,0 +1,149 @@
+use std::{
+    collections::HashMap,
+    thread,
+    time::{Duration, Instant},
+};
+
+use dota_smash::{loopback::LoopbackNetwork, NetworkConditions};
+use ggrs::{
+    Config, GGRSError, GGRSRequest, P2PSession, PlayerType, SessionBuilder,
+    SessionState,
+};
+
+const FRAME: Duration = Duration::from_millis(16);
+const TIMEOUT: Duration = Duration::from_secs(30);
+const FRAMES: u32 = 120;
+// Fixed, so failures under bad conditions reproduce
+const SEED: u64 = 0x5eed;
+
+struct TestConfig;
+
+impl Config for TestConfig {
+    type Input = u8;
+    /// Sum of all inputs so far
+    type State = u32;
+    type Address = String;
+}
+
+/// What a peer simulated so far.
+#[derive(Default)]
+struct Peer {
+    state: u32,
+    /// Current frame, goes back on rollbacks
+    frame: u32,
+    /// State at the end of each frame, overwritten by resimulations
+    states: HashMap<u32, u32>,
+    rollbacks: u32,
+}
+
+impl Peer {
+    fn handle(&mut self, requests: Vec<GGRSRequest<TestConfig>>) {
+        for request in requests {
+            match request {
+                GGRSRequest::SaveGameState { cell, frame } => {
+                    cell.save(frame, Some(self.state), None);
+                }
+                GGRSRequest::LoadGameState { cell, frame } => {
+                    self.state = cell.load().unwrap();
+                    self.frame = frame as u32;
+                    self.rollbacks += 1;
+                }
+                GGRSRequest::AdvanceFrame { inputs } => {
+                    for (input, _) in inputs {
+                        self.state += input as u32;
+                    }
+                    self.frame += 1;
+                    self.states.insert(self.frame, self.state);
+                }
+            }
+        }
+    }
+}
+
+fn session(
+    network: &LoopbackNetwork,
+    local: usize,
+    addrs: &[&str],
+) -> P2PSession<TestConfig> {
+    let mut builder =
+        SessionBuilder::<TestConfig>::new().with_num_players(addrs.len());
+
+    for (handle, addr) in addrs.iter().enumerate() {
+        let player = if handle == local {
+            PlayerType::Local
+        } else {
+            PlayerType::Remote(addr.to_string())
+        };
+        builder = builder.add_player(player, handle).unwrap();
+    }
+
+    builder
+        .start_p2p_session(network.socket(addrs[local]))
+        .unwrap()
+}
+
+/// Runs every peer until `FRAMES` frames are confirmed, each pressing its
+/// own pattern. Checks that all peers ended up with the same state.
+fn play(conditions: NetworkConditions) -> Vec<Peer> {
+    let network = LoopbackNetwork::new(conditions, SEED);
+    let addrs = ["a", "b"];
+    let mut sessions: Vec<_> = (0..addrs.len())
+        .map(|local| session(&network, local, &addrs))
+        .collect();
+    let mut peers: Vec<Peer> =
+        sessions.iter().map(|_| Peer::default()).collect();
+
+    let started = Instant::now();
+    // Until then a rollback may still change the state of the frame
+    while sessions
+        .iter()
+        .any(|session| session.confirmed_frame() < FRAMES as i32)
+    {
+        assert!(started.elapsed() < TIMEOUT, "Peers didn't finish in time");
+
+        for (handle, session) in sessions.iter_mut().enumerate() {
+            session.poll_remote_clients();
+            if session.current_state() != SessionState::Running {
+                continue;
+            }
+
+            let input = (peers[handle].frame % (handle as u32 + 3)) as u8;
+            session.add_local_input(handle, input).unwrap();
+
+            match session.advance_frame() {
+                Ok(requests) => peers[handle].handle(requests),
+                Err(GGRSError::PredictionThreshold) => {}
+                Err(e) => panic!("{}", e),
+            }
+        }
+
+        thread::sleep(FRAME);
+    }
+
+    let state = peers[0].states[&FRAMES];
+    for peer in peers.iter() {
+        assert_eq!(peer.states[&FRAMES], state);
+    }
+
+    peers
+}
+
+#[test]
+fn peers_connect_without_a_server() {
+    let peers = play(NetworkConditions::default());
+    assert!(peers.iter().all(|peer| peer.frame >= FRAMES));
+}
+
+#[test]
+fn peers_roll_back_under_bad_conditions() {
+    let peers = play(NetworkConditions {
+        latency: Duration::from_millis(60),
+        jitter: Duration::from_millis(20),
+        loss: 0.05,
+        duplicate: 0.05,
+        reorder: 0.05,
+    });
+
+    assert!(peers.iter().all(|peer| peer.frame >= FRAMES));
+    assert!(peers.iter().any(|peer| peer.rollbacks > 0));
+}
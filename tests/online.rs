@@ -0,0 +1,73 @@
+use std::{
+    thread,
+    time::{Duration, Instant},
+};
+
+use dota_smash::{
+    harness::{BoxInput, InputScript, MatchHarness, INPUT_ATTACK, INPUT_LEFT},
+    loopback::LoopbackNetwork,
+    GameConfig, NetworkConditions,
+};
+
+const UPDATE_INTERVAL: Duration = Duration::from_millis(1);
+const TIMEOUT: Duration = Duration::from_secs(30);
+const FRAMES: u32 = 120;
+// Fixed, so failures under bad conditions reproduce
+const SEED: u64 = 0x5eed;
+
+/// Two headless apps playing each other over a network with `conditions`,
+/// until both confirmed `FRAMES` frames.
+fn play(conditions: NetworkConditions) -> Vec<MatchHarness> {
+    // Every peer plays its own handle from the same script
+    let script = InputScript::default()
+        .tap(0, 10, INPUT_ATTACK)
+        .hold(1, 5, BoxInput::new(INPUT_LEFT, Default::default()))
+        .tap(1, 40, INPUT_ATTACK);
+
+    let network = LoopbackNetwork::new(conditions, SEED);
+    let mut peers: Vec<MatchHarness> = (0..2)
+        .map(|_| {
+            MatchHarness::online(
+                GameConfig::default(),
+                script.clone(),
+                &network,
+            )
+        })
+        .collect();
+    MatchHarness::start_together(&mut peers);
+
+    let started = Instant::now();
+    while peers.iter().any(|peer| peer.confirmed_frames() < FRAMES) {
+        assert!(started.elapsed() < TIMEOUT, "Peers didn't finish in time");
+
+        for peer in peers.iter_mut() {
+            peer.update();
+        }
+        thread::sleep(UPDATE_INTERVAL);
+    }
+
+    peers
+}
+
+#[test]
+fn headless_apps_play_each_other() {
+    let peers = play(NetworkConditions::default());
+
+    let checksum = peers[0].checksum(FRAMES);
+    assert!(checksum.is_some());
+    assert!(peers.iter().all(|peer| peer.checksum(FRAMES) == checksum));
+}
+
+#[test]
+fn headless_apps_agree_under_bad_conditions() {
+    let peers = play(NetworkConditions {
+        latency: Duration::from_millis(40),
+        jitter: Duration::from_millis(10),
+        loss: 0.05,
+        ..Default::default()
+    });
+
+    let checksum = peers[0].checksum(FRAMES);
+    assert!(checksum.is_some());
+    assert!(peers.iter().all(|peer| peer.checksum(FRAMES) == checksum));
+}
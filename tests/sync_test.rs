@@ -0,0 +1,51 @@
+use dota_smash::{
+    harness::{
+        BoxInput, InputScript, MatchHarness, INPUT_ATTACK, INPUT_LEFT,
+        INPUT_RIGHT, INPUT_UP,
+    },
+    GameConfig,
+};
+
+const FRAMES: u32 = 120;
+
+/// Runs `script` under a sync test. The session panics on the first frame
+/// whose resimulation doesn't match its first simulation.
+fn sync_test(check_distance: usize, script: InputScript) -> MatchHarness {
+    let config = GameConfig {
+        sync_test: Some(check_distance),
+        ..Default::default()
+    };
+    let mut harness = MatchHarness::new(config, script);
+    harness.advance_to(FRAMES);
+    harness
+}
+
+/// Both players move, jump and trade fireballs.
+fn busy_script() -> InputScript {
+    InputScript::default()
+        .hold(0, 5, BoxInput::new(INPUT_RIGHT, Default::default()))
+        .tap(0, 20, INPUT_UP)
+        .tap(0, 30, INPUT_ATTACK)
+        .hold(1, 10, BoxInput::new(INPUT_LEFT, Default::default()))
+        .tap(1, 25, INPUT_ATTACK)
+        .tap(1, 60, INPUT_UP)
+        .tap(0, 80, INPUT_ATTACK)
+}
+
+#[test]
+fn idle_match_resimulates_the_same() {
+    let harness = sync_test(2, InputScript::default());
+    assert!(harness.frame() >= FRAMES);
+}
+
+#[test]
+fn scripted_match_resimulates_the_same() {
+    let harness = sync_test(2, busy_script());
+    assert!(harness.frame() >= FRAMES);
+}
+
+#[test]
+fn scripted_match_resimulates_the_same_far_back() {
+    let harness = sync_test(7, busy_script());
+    assert!(harness.frame() >= FRAMES);
+}